@@ -6,19 +6,28 @@ use std::sync::{Arc, RwLock};
 use log::{debug, info};
 use rayon::prelude::IntoParallelRefIterator;
 
+use hub::cancel;
 use hub::config::BuildMode;
-use hub::config::Partition;
+use hub::config::{ConfigFile, Partition};
 use hub::error::*;
+use hub::paths::PathManager;
 
 use crate::{BuildStatus, DependencyTree};
+use crate::initfs::build_initfs;
 
 pub fn build_partition<'a>(
+    config: &ConfigFile,
+    paths: &PathManager,
     partition: Arc<Partition>,
     resolved_dependencies: HashMap<String, Arc<RwLock<DependencyTree>>>,
     // cx: &mut Context<'a>,
 ) -> Result<()> {
     info!("Building Partition {}", &partition.label);
 
+    for component in resolved_dependencies.values() {
+        build_builtin(config, paths, component)?;
+    }
+
     // let components = futures::future::join_all(
     //     resolved_dependencies
     //         .values()
@@ -91,6 +100,39 @@ pub fn build_partition<'a>(
 //     }
 // }
 
+/// Builds the components which the builder assembles itself rather than through a build script, once each.
+/// Their dependencies are built first. Partitions sharing a component wait for whichever started building it.
+fn build_builtin(config: &ConfigFile, paths: &PathManager, component: &Arc<RwLock<DependencyTree>>) -> Result<()> {
+    let mut component = component.write().expect("Dependency tree lock poisoned");
+
+    match &component.status {
+        BuildStatus::NotStarted => {}
+        BuildStatus::Failure => return Err(BuildError::FailedDependency(component.component.name.clone()).into()),
+        BuildStatus::InProgress | BuildStatus::Success(_) => return Ok(()),
+    }
+
+    for dependency in component.dependencies.iter() {
+        build_builtin(config, paths, dependency)?;
+    }
+
+    cancel::checkpoint()?;
+
+    let built = match &component.component.build_mode {
+        BuildMode::Initfs(initfs) => build_initfs(config, paths, &component.component, initfs),
+        BuildMode::Cargo(_) | BuildMode::Shell(_) => return Ok(()),
+    };
+
+    match built {
+        Ok(artifacts) => component.status = BuildStatus::Success(artifacts),
+        Err(err) => {
+            component.status = BuildStatus::Failure;
+            return Err(err);
+        }
+    }
+
+    Ok(())
+}
+
 #[derive(Debug, Clone)]
 pub struct ArtifactList {
     pub component: String,
@@ -8,20 +8,26 @@ use std::fs;
 use std::path::PathBuf;
 use std::sync::Arc;
 
-use log::info;
+use log::{error, info};
 
-use hub::config::ConfigFile;
+use hub::config::{Backend, ConfigFile};
 use hub::config::ImageFormat;
 use hub::error::*;
 use hub::paths::PathManager;
 use img::DiskManager;
-use img::mnt::mount_filesystems;
+use img::filesystem::Registry;
+use img::mnt::{mount_filesystems, MountHandle};
 use img::preload_filesystems;
+use img::recover::{BuildLock, recover};
 
 pub struct Context {
     pub disk_mgr: OnceCell<Box<dyn DiskManager>>,
+    pub mounts: Option<MountHandle>,
+    pub drivers: Registry,
     pub env: Arc<HashMap<String, OsString>>,
     pub paths: Arc<PathManager>,
+    // Declared last so the build directory stays claimed until everything else has been torn down
+    pub lock: BuildLock,
 }
 
 impl Context {
@@ -32,8 +38,17 @@ impl Context {
 
 impl Drop for Context {
     fn drop(&mut self) {
+        // Filesystems have to be unmounted before the partitions they live on disappear
+        if let Some(mut mounts) = self.mounts.take() {
+            if let Err(err) = mounts.unmount_all() {
+                error!("Failed to unmount filesystems: {:?}", err);
+            }
+        }
+
         if let Some(mut mgr) = self.disk_mgr.take() {
-            mgr.unmount().expect("Failed to unbind storage daemon");
+            if let Err(err) = mgr.unmount() {
+                error!("Failed to unmount disk: {:?}", err);
+            }
         }
     }
 }
@@ -41,6 +56,8 @@ impl Drop for Context {
 pub fn mk_context(
     config: Arc<ConfigFile>,
     path: Arc<PathManager>,
+    backend: Option<Backend>,
+    fuse: bool,
 ) -> Result<Context> {
     let mut env = HashMap::new();
 
@@ -48,6 +65,10 @@ pub fn mk_context(
     let final_image = path.final_image();
 
     fs::create_dir_all(&build_dir)?;
+
+    let lock = BuildLock::acquire(&path)?;
+    recover(&path, &lock)?;
+
     info!("Final Build Image: {:?}", &final_image);
 
     for i in config
@@ -62,15 +83,24 @@ pub fn mk_context(
     env.insert("BUILD_DIR".to_owned(), build_dir.clone().into_os_string());
     env.insert("IMAGE".to_owned(), final_image.clone().into_os_string());
 
-    let mut cell = OnceCell::new();
-    cell.set(preload_filesystems(Arc::clone(&config.image), Arc::clone(&path))?).map_err(|err| Error::from(BuildError::FailedToCreateImage))?;
+    let drivers = Registry::new(&config, Arc::clone(&path));
 
-    info!("Mounting partitions");
-    mount_filesystems(Arc::clone(&config.image), Arc::clone(&path))?;
+    let mut cell = OnceCell::new();
+    cell.set(preload_filesystems(Arc::clone(&config.image), &drivers, Arc::clone(&path), backend, fuse)?).map_err(|err| Error::from(BuildError::FailedToCreateImage))?;
 
-    Ok(Context {
+    let mut cx = Context {
         disk_mgr: cell,
+        mounts: None,
+        drivers,
         env: Arc::new(env),
         paths: Arc::clone(&path),
-    })
+        lock,
+    };
+
+    // If this fails, dropping the context releases the disk
+    info!("Mounting partitions");
+    let disk = cx.disk_mgr.get().expect("Disk manager was set above");
+    cx.mounts = Some(mount_filesystems(Arc::clone(&config.image), disk.as_ref(), &cx.drivers, Arc::clone(&path))?);
+
+    Ok(cx)
 }
\ No newline at end of file
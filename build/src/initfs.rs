@@ -0,0 +1,77 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::Arc;
+
+use log::info;
+
+use hub::cancel;
+use hub::config::{Component, ConfigFile, Initfs};
+use hub::error::*;
+use hub::global::Inner;
+use hub::paths::PathManager;
+use img::mnt::Mount;
+use img::place::place_entries;
+
+use crate::builder::ArtifactList;
+
+/// Packs an initfs component. Its files are staged in `PathManager::initfs` and then archived by `redox-initfs-ar`.
+/// The archive is emitted as the component's first artifact, or `initfs` if it doesn't name any.
+/// The stage is cleared without further locking. It's only safe because the build directory is claimed through
+/// `PathManager::lock_file` and component names are unique, so no other job stages into the same directory.
+pub fn build_initfs(config: &ConfigFile, paths: &PathManager, component: &Component, initfs: &Initfs) -> Result<ArtifactList> {
+    info!("Assembling initfs '{}'", &component.name);
+
+    let stage = paths.initfs(&component.name);
+    if stage.exists() {
+        fs::remove_dir_all(&stage)?;
+    }
+    fs::create_dir_all(&stage)?;
+
+    let mount = Mount::directory(component.name.clone(), stage.clone());
+    place_entries(config, &initfs.files, &mount, paths)?;
+
+    let artifact = component.yields.first().cloned().unwrap_or(PathBuf::from("initfs"));
+    let output = paths.artifacts().join(&component.name).join(&artifact);
+    if let Some(parent) = output.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut archive = Command::new("redox-initfs-ar");
+    if let Some(max_size) = initfs.max_size {
+        let bytes = u64::try_from(max_size)
+            .ok()
+            .and_then(|max_size| max_size.checked_mul(1024 * 1024))
+            .ok_or(BuildError::InvalidInitfsSize(max_size))?;
+
+        archive.arg("--max-size").arg(bytes.to_string());
+    }
+
+    archive.arg(&stage);
+
+    if let Some(bootstrap) = initfs.bootstrap.as_ref() {
+        let bootstrap = paths
+            .artifact(bootstrap)
+            .filter(|path| path.is_file())
+            .ok_or(BuildError::MissingArtifact(bootstrap.clone()))?;
+
+        archive.arg(bootstrap);
+    }
+
+    let status = match cancel::run(archive.arg("--output").arg(&output)) {
+        Err(err) if matches!(err.inner(), Inner::IoError(io) if io.kind() == ErrorKind::NotFound) => {
+            return Err(BuildError::HostToolMissing("redox-initfs-ar".into()).into());
+        }
+        status => status?,
+    };
+
+    if !status.success() {
+        return Err(BuildError::ToolFailed("redox-initfs-ar".into(), status).into());
+    }
+
+    Ok(ArtifactList {
+        component: component.name.clone(),
+        artifacts: Arc::new(Box::new([output])),
+    })
+}
@@ -12,16 +12,21 @@ use rayon::iter::ParallelIterator;
 use rayon::prelude::IntoParallelRefIterator;
 use serde::de::DeserializeOwned;
 
-use hub::config::{Component, ConfigFile};
+use hub::cancel;
+use hub::config::{Backend, Component, ConfigFile};
 use hub::config::ImportableModule;
 use hub::error::*;
 use hub::paths::PathManager;
+use img::place::{embed_config, place_files};
+use img::recover::{BuildLock, recover};
+use img::users::place_users;
 
 use crate::builder::{ArtifactList, build_partition};
 use crate::cx::mk_context;
 
 pub mod builder;
 pub mod cx;
+pub mod initfs;
 
 #[derive(Debug)]
 pub struct DependencyTree {
@@ -55,7 +60,7 @@ fn read_toml_file<T: DeserializeOwned, FilePath: AsRef<Path>>(path: FilePath) ->
 }
 
 pub fn build<RequestedBuildDir: AsRef<Path>>(
-    config_path: PathBuf, clean: bool, build_dir: Option<RequestedBuildDir>,
+    config_path: PathBuf, clean: bool, build_dir: Option<RequestedBuildDir>, backend: Option<Backend>, fuse: bool,
 ) -> Result<()> {
     let mut config: ConfigFile = read_toml_file(&config_path)?;
     info!("Beginning build '{}'", &config.name);
@@ -73,12 +78,18 @@ pub fn build<RequestedBuildDir: AsRef<Path>>(
         })?;
 
         config.components.extend(r#mod.components);
+
+        // Values set by the parent take precedence over imported ones
+        for (name, value) in r#mod.variables {
+            config.variables.entry(name).or_insert(value);
+        }
     }
 
     debug!("Preparing Environment");
     let config = Arc::new(config);
     let path = Arc::new(PathManager::new(Arc::clone(&config), build_dir));
-    let mut cx = mk_context(Arc::clone(&config), Arc::clone(&path))?;
+    let mut cx = mk_context(Arc::clone(&config), Arc::clone(&path), backend, fuse)?;
+    cancel::checkpoint()?;
 
     {
         let mut check_duplicates = HashSet::<String>::new();
@@ -93,14 +104,17 @@ pub fn build<RequestedBuildDir: AsRef<Path>>(
 
     debug!("Building Dependency Graph");
     let dependency_graph = build_dependency_graph(&config)?;
+    cancel::checkpoint()?;
 
     let dep = &dependency_graph;
-    let items = config
+    config
         .image
         .partitions
         .par_iter()
         .map(|i| Arc::new(i.clone()))
-        .map(|partition| build_partition(
+        .map(|partition| cancel::checkpoint().and_then(|_| build_partition(
+            &config,
+            &path,
             Arc::clone(&partition),
             partition.requires.iter()
                 .filter_map(|i| match dep.get(i) {
@@ -108,12 +122,46 @@ pub fn build<RequestedBuildDir: AsRef<Path>>(
                     None => None,
                 })
                 .collect(),
-        ))
-        .collect::<Result<Vec<_>>>();
-    // TODO: Check for errors
+        )))
+        .collect::<Result<Vec<_>>>()?;
 
     info!("All partitions built");
 
+    // Files are placed once everything the partitions require has been built
+    let mounts = cx.mounts.as_ref().expect("Filesystems are mounted with the context");
+    place_users(&config, mounts)?;
+
+    for partition in config.image.partitions.iter() {
+        cancel::checkpoint()?;
+        place_files(&config, partition, mounts, &path)?;
+    }
+
+    embed_config(&config, mounts, &path)?;
+
+    Ok(())
+}
+
+/// Cleans up mounts, daemons and loop devices left behind by an interrupted build.
+/// Unless `unmount_only` is set, the build directory is then removed.
+pub fn clean<RequestedBuildDir: AsRef<Path>>(
+    config_path: PathBuf, build_dir: Option<RequestedBuildDir>, unmount_only: bool,
+) -> Result<()> {
+    let config: ConfigFile = read_toml_file(&config_path)?;
+    let path = PathManager::new(Arc::new(config), build_dir);
+
+    if !path.build_dir().exists() {
+        info!("Nothing to clean");
+        return Ok(());
+    }
+
+    let lock = BuildLock::acquire(&path)?;
+    recover(&path, &lock)?;
+
+    if !unmount_only {
+        info!("Removing {:?}", path.build_dir());
+        fs::remove_dir_all(path.build_dir())?;
+    }
+
     Ok(())
 }
 
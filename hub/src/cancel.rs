@@ -0,0 +1,69 @@
+use std::process::{Child, Command, ExitStatus, Output};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use log::warn;
+
+use crate::error::*;
+
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+/// Processes started on behalf of a component job. They're asked to stop as soon as the build is interrupted.
+static JOBS: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+/// Installs a handler for Ctrl-C and SIGTERM. Rather than exiting immediately, the build is marked as cancelled and
+/// running jobs are stopped. The build then fails at its next checkpoint, which unwinds through the normal teardown.
+pub fn install() -> Result<()> {
+    ctrlc::set_handler(|| {
+        if CANCELLED.swap(true, Ordering::SeqCst) {
+            warn!("Already shutting down. Waiting for filesystems to be unmounted");
+            return;
+        }
+
+        warn!("Interrupted. Cleaning up");
+
+        for pid in JOBS.lock().expect("Job registry poisoned").iter() {
+            unsafe { libc::kill(*pid as libc::pid_t, libc::SIGTERM) };
+        }
+    })
+    .map_err(|err| Error::from(anyhow::Error::new(err)))
+}
+
+/// Whether the build has been interrupted
+pub fn cancelled() -> bool {
+    CANCELLED.load(Ordering::SeqCst)
+}
+
+/// Fails with `BuildError::Interrupted` if the build has been interrupted. Called between stages of the build.
+pub fn checkpoint() -> Result<()> {
+    match cancelled() {
+        true => Err(BuildError::Interrupted.into()),
+        false => Ok(()),
+    }
+}
+
+/// Runs a job to completion. The process is stopped if the build is interrupted in the meantime.
+pub fn run(command: &mut Command) -> Result<ExitStatus> {
+    Ok(output(command)?.status)
+}
+
+/// Like `run`, but collects whatever the job wrote to any streams configured as `Stdio::piped`
+pub fn output(command: &mut Command) -> Result<Output> {
+    // The job is checked for, started and registered under the lock the interrupt handler takes, so an interrupt can't
+    // slip in between the checkpoint and the job becoming visible to the handler
+    let (child, pid) = {
+        let mut jobs = JOBS.lock().expect("Job registry poisoned");
+        checkpoint()?;
+
+        let child: Child = command.spawn()?;
+        let pid = child.id();
+        jobs.push(pid);
+        (child, pid)
+    };
+
+    let output = child.wait_with_output();
+    JOBS.lock().expect("Job registry poisoned").retain(|job| *job != pid);
+
+    checkpoint()?;
+    Ok(output?)
+}
@@ -1,6 +1,8 @@
+use std::collections::BTreeMap;
 use std::path::PathBuf;
 use std::sync::Arc;
 
+use clap::ValueEnum;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -16,14 +18,33 @@ pub struct ConfigFile {
 
     #[serde(default, rename = "filesystem")]
     pub filesystems: Vec<Filesystem>,
+
+    /// Free-form values which templates can refer to
+    #[serde(default)]
+    pub variables: BTreeMap<String, String>,
+
+    #[serde(default, rename = "user")]
+    pub users: Vec<User>,
+    #[serde(default, rename = "group")]
+    pub groups: Vec<Group>,
 }
 
+impl ConfigFile {
+    /// The config as it's built, with its imports already merged in
+    pub fn resolved(&self) -> crate::Result<String> {
+        let mut config = self.clone();
+        config.requires.clear();
+
+        Ok(toml::to_string(&config)?)
+    }
+}
+
+/// A user-defined filesystem. Its script defines the `create`, `mount`, `umount` and `stat` functions which are
+/// called for partitions naming it as their filesystem.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Filesystem {
-    create: String,
-    mount: String,
-    unmount: String,
-    get_usage: String,
+    pub name: String,
+    pub shell: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -40,6 +61,80 @@ pub struct ImageConfig {
 
     #[serde(default, rename = "partition-table")]
     pub partition_mode: PartitionMode,
+
+    /// The modification time of placed files in ms since the UNIX epoch, unless they set their own.
+    /// Falls back to `$SOURCE_DATE_EPOCH` and then the time of the build.
+    pub mtime: Option<u64>,
+
+    /// Writes the resolved config into the image, recording how it was built. Off unless set
+    pub embed: Option<Embed>,
+
+    /// The label of the partition holding the root filesystem, which users and groups are written to.
+    /// Defaults to the first partition formatted as `redoxfs`.
+    pub root: Option<String>,
+}
+
+impl ImageConfig {
+    pub fn root_partition(&self) -> Option<&Partition> {
+        match self.root.as_ref() {
+            Some(root) => self.partitions.iter().find(|partition| &partition.label == root),
+            None => self
+                .partitions
+                .iter()
+                .find(|partition| partition.filesystem.as_ref().is_some_and(|fs| fs.eq_ignore_ascii_case("redoxfs"))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Embed {
+    /// The label of the partition to write the config to
+    pub partition: String,
+    #[serde(default = "Embed::default_path")]
+    pub path: PathBuf,
+    /// Where the `Lock` is written, in the same partition as the config
+    #[serde(default = "Embed::default_lock")]
+    pub lock: PathBuf,
+}
+
+impl Embed {
+    fn default_path() -> PathBuf {
+        PathBuf::from("/filesystem.toml")
+    }
+
+    fn default_lock() -> PathBuf {
+        PathBuf::from("/filesystem.lock")
+    }
+}
+
+/// What the config alone doesn't pin down about a build: the version of each component and a hash of every artifact
+/// it emitted
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Lock {
+    #[serde(default, rename = "component")]
+    pub components: Vec<LockedComponent>,
+}
+
+impl Lock {
+    pub fn serialise(&self) -> crate::Result<String> {
+        Ok(toml::to_string(self)?)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LockedComponent {
+    pub name: String,
+    pub version: Option<String>,
+    #[serde(default, rename = "artifact")]
+    pub artifacts: Vec<LockedArtifact>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LockedArtifact {
+    /// Relative to the component's directory in the artifact store
+    pub path: PathBuf,
+    /// The hex-encoded SHA-256 hash of the artifact
+    pub sha256: String,
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
@@ -70,6 +165,21 @@ impl Default for ImageFormat {
     }
 }
 
+/// The mechanism used to expose the image's partitions to the builder
+#[derive(Debug, Clone, Copy, Eq, PartialEq, ValueEnum)]
+pub enum Backend {
+    /// Export the image through `qemu-storage-daemon` and expose its partitions through PartitionFS
+    #[cfg(feature = "qemu")]
+    QemuFuse,
+    /// Expose the partitions of a raw image through PartitionFS
+    RawFuse,
+    /// Attach a raw image to a loop device. Requires superuser access
+    Loop,
+    /// Export the image over NBD from `qemu-storage-daemon` and access it in-process. Requires neither FUSE nor kernel mounts
+    #[cfg(feature = "qemu")]
+    Nbd,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Partition {
     #[serde(default)]
@@ -82,16 +192,92 @@ pub struct Partition {
 
     pub filesystem: Option<String>,
 
+    /// Encrypts the filesystem with a password. Only supported by filesystems which implement encryption.
+    pub encryption: Option<Encryption>,
+
+    /// Only used if `filesystem` is `redoxfs`
+    #[serde(default)]
+    pub redoxfs: RedoxFSOptions,
+
     /// This parameter is only useful if `filesystem` is defined.
     /// If set, will mount the filesystem and place all resources into it.
     #[serde(default, rename = "file")]
     pub files: Vec<File>,
 }
 
+/// How a RedoxFS filesystem is created
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RedoxFSOptions {
+    /// An artifact written to the area reserved at the start of the filesystem, such as a bootloader
+    pub reserved: Option<String>,
+    /// The creation time of the filesystem in ms since the UNIX epoch. Defaults to `ImageConfig::mtime` and its fallbacks
+    pub ctime: Option<u64>,
+    /// Pins the filesystem's UUID so references to it stay stable between builds. Random if unset
+    pub uuid: Option<String>,
+}
+
+/// Where the password of an encrypted partition comes from. Passwords are never stored in the config itself.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Encryption {
+    /// A file containing the password. A trailing newline is ignored
+    PasswordFile(PathBuf),
+    /// An environment variable containing the password
+    PasswordEnv(String),
+}
+
+impl Encryption {
+    pub fn password(&self) -> crate::Result<Vec<u8>> {
+        let password = match self {
+            Self::PasswordFile(path) => {
+                let mut password = std::fs::read(path)?;
+                while password.last().is_some_and(|byte| *byte == b'\n' || *byte == b'\r') {
+                    password.pop();
+                }
+
+                password
+            }
+            Self::PasswordEnv(var) => std::env::var_os(var).unwrap_or_default().into_encoded_bytes(),
+        };
+
+        match password.is_empty() {
+            true => Err(crate::BuildError::PasswordUnavailable(self.to_string()).into()),
+            false => Ok(password),
+        }
+    }
+}
+
+impl std::fmt::Display for Encryption {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::PasswordFile(path) => write!(f, "{:?}", path),
+            Self::PasswordEnv(var) => write!(f, "${}", var),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct File {
     pub path: PathBuf,
 
+    /// Permission bits including the setuid, setgid and sticky bits, eg. `0o4755`
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// Resolved against `/etc/passwd` in the partition. Mutually exclusive to `uid`
+    pub user: Option<String>,
+    /// Resolved against `/etc/group` in the partition. Mutually exclusive to `gid`
+    pub group: Option<String>,
+    /// The modification time in ms since the UNIX epoch. Defaults to `ImageConfig::mtime`
+    pub mtime: Option<u64>,
+
+    /// Globs matched against paths relative to a `directory` or `archive`. If any are given, only matching entries are placed
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Globs matched against paths relative to a `directory` or `archive`. Excluding a directory excludes its contents
+    #[serde(default)]
+    pub exclude: Vec<String>,
+
     #[serde(flatten)]
     pub content: FilesystemEntry,
 }
@@ -107,11 +293,68 @@ pub enum FilesystemEntry {
     // },
     Artifact(String),
     Shell(String),
+    /// Text with `{{ ... }}` placeholders, which are filled in from the build when the file is placed
+    Template(String),
+    /// A directory copied recursively. Either a path on the host or an artifact reference
+    Directory(String),
+    /// A tar, tar.zst or zip archive extracted at the path. Either a path on the host or an artifact reference
+    Archive(String),
+    /// An empty directory
+    Mkdir(bool),
+}
+
+/// An account written to the root partition's `/etc/passwd` and `/etc/shadow`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct User {
+    pub name: String,
+    pub uid: u32,
+    /// The user's primary group. Defaults to `uid`
+    pub gid: Option<u32>,
+    /// Defaults to `name`
+    pub full_name: Option<String>,
+    /// Created and owned by the user. Defaults to `/home/<name>`, or `/root` for UID 0
+    pub home: Option<PathBuf>,
+    #[serde(default = "User::default_shell")]
+    pub shell: PathBuf,
+    /// The hashed password, as it appears in `/etc/shadow`. Without one, no password is needed to log in
+    pub password: Option<String>,
+    /// Prevents logging in as the user at all. Mutually exclusive to `password`
+    #[serde(default)]
+    pub locked: bool,
+}
+
+impl User {
+    fn default_shell() -> PathBuf {
+        PathBuf::from("/bin/ion")
+    }
+
+    pub fn gid(&self) -> u32 {
+        self.gid.unwrap_or(self.uid)
+    }
+
+    pub fn home(&self) -> PathBuf {
+        match (self.home.as_ref(), self.uid) {
+            (Some(home), _) => home.clone(),
+            (None, 0) => PathBuf::from("/root"),
+            (None, _) => PathBuf::from("/home").join(&self.name),
+        }
+    }
+}
+
+/// A group written to the root partition's `/etc/group`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Group {
+    pub name: String,
+    pub gid: u32,
+    /// The names of users belonging to the group besides those for which it's the primary group
+    #[serde(default)]
+    pub members: Vec<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Component {
     pub name: String,
+    pub version: Option<String>,
     #[serde(default)]
     pub requires: Vec<String>,
     #[serde(default)]
@@ -129,6 +372,20 @@ pub struct Component {
 pub enum BuildMode {
     Cargo(Vec<String>),
     Shell(String),
+    /// Assembled by the builder itself rather than a build script
+    Initfs(Initfs),
+}
+
+/// A Redox initfs, packed from files staged the same way as those placed into a partition
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Initfs {
+    /// The artifact containing the bootstrap executable, which is prepended to the archive
+    pub bootstrap: Option<String>,
+    /// The largest the initfs may grow to in MiB
+    pub max_size: Option<i64>,
+
+    #[serde(default, rename = "file")]
+    pub files: Vec<File>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -160,4 +417,6 @@ impl Default for CacheMode {
 pub struct ImportableModule {
     #[serde(rename = "component", default)]
     pub components: Vec<Component>,
+    #[serde(default)]
+    pub variables: BTreeMap<String, String>,
 }
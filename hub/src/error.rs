@@ -1,5 +1,3 @@
-use std::any::Any;
-
 pub use global::Error;
 
 macro_rules! multi_error {
@@ -27,6 +25,10 @@ macro_rules! multi_error {
                 pub fn into_inner(self) -> Inner {
                     self.inner
                 }
+
+                pub fn inner(&self) -> &Inner {
+                    &self.inner
+                }
             }
 
             impl<Err> From<Err> for Error where Err: Into<Inner> {
@@ -61,6 +63,7 @@ multi_error! { global();
     BuildError = crate::error::BuildError;
     IoError = std::io::Error;
     TomlParseError = toml::de::Error;
+    TomlSerialiseError = toml::ser::Error;
     JsonError = serde_json::Error;
     JoinError = tokio::task::JoinError;
     Anyhow = anyhow::Error;
@@ -81,9 +84,14 @@ pub enum BuildError {
     LoopError,
     FailedToCreateImage,
     #[cfg(feature = "qemu")]
-    QmpQuitFail(Box<dyn Any + Send>),
+    QmpConnectionClosed,
+    #[cfg(feature = "qemu")]
+    QmpTimeout,
+    // The error class and description reported by QEMU
     #[cfg(feature = "qemu")]
-    QmpQuitWrite0,
+    QmpError(String, String),
+    #[cfg(feature = "qemu")]
+    QmpUnexpectedMessage(serde_json::Value),
     InvalidDiskType,
     InvalidPartitionName,
     // FuseError(Box<dyn Any + Send>),
@@ -91,6 +99,62 @@ pub enum BuildError {
     UnrecognisedFilesystem(String),
     FailedToCreateFilesystem(String),
     NoPartitionMountPoint(String),
+    BackendUnavailable(crate::config::Backend, String),
+    NoBackendAvailable(crate::config::ImageFormat),
+    NbdError(String),
+    HostToolMissing(String),
+    // The daemon's exit status and everything it wrote to stderr
+    #[cfg(feature = "qemu")]
+    StorageDaemonExited(std::process::ExitStatus, String),
+    #[cfg(feature = "qemu")]
+    StorageDaemonTimeout(String),
+    // The build was cancelled by Ctrl-C or SIGTERM
+    Interrupted,
+    // The build directory and the PID of the build currently using it
+    BuildDirLocked(std::path::PathBuf, String),
+    // A mount point left behind by an earlier build which couldn't be removed
+    StaleMount(std::path::PathBuf),
+    // The shell function which was called and the status it exited with
+    ShellFailed(String, std::process::ExitStatus),
+    // The shell function which was called and what it printed
+    InvalidShellOutput(String, String),
+    // A path which can't be represented on the partition's filesystem
+    InvalidFilePath(std::path::PathBuf),
+    // The filesystem and the operation it can't perform
+    UnsupportedOperation(String, String),
+    // Where the password of an encrypted partition was supposed to come from
+    PasswordUnavailable(String),
+    // The artifact reference which couldn't be found in the artifact store
+    MissingArtifact(String),
+    // A partition with files to place but no filesystem to place them in
+    NoFilesystem(String),
+    // The user or group name which couldn't be found in the partition's `/etc/passwd` or `/etc/group`
+    UnknownOwner(String),
+    // The file whose ownership was given both by ID and by name
+    ConflictingOwner(std::path::PathBuf),
+    InvalidUuid(String),
+    // The glob and why it couldn't be parsed
+    InvalidGlob(String, String),
+    // An archive whose format can't be told from its name
+    UnsupportedArchive(std::path::PathBuf),
+    // The template placeholder which couldn't be filled in
+    InvalidTemplate(String),
+    // The user or group name which is defined more than once
+    DuplicateOwner(String),
+    // The user which was given both a password and locked
+    ConflictingPassword(String),
+    // Users or groups were defined, but no partition holds the root filesystem
+    NoRootPartition,
+    // The number of sectors of a disk too small to hold a GUID partition table and any partitions
+    DiskTooSmall(u64),
+    // The number of partitions given, which is more than a GUID partition table has entries for
+    TooManyPartitions(usize),
+    // The partition which is empty or doesn't fit in the space left on the disk
+    PartitionTooLarge(String),
+    // The maximum size of an initfs in MiB, which is negative or too large to be given in bytes
+    InvalidInitfsSize(i64),
+    // The host tool which failed and the status it exited with
+    ToolFailed(String, std::process::ExitStatus),
 }
 
 impl std::error::Error for BuildError {}
@@ -4,4 +4,6 @@ pub mod error;
 pub mod config;
 pub mod reporter;
 pub mod paths;
+pub mod cancel;
+pub mod shell;
 
@@ -1,6 +1,6 @@
 use std::env::current_dir;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 
 use convert_case::Casing;
 
@@ -9,6 +9,7 @@ use crate::config::{ConfigFile, ImageFormat};
 pub struct PathManager {
     config: Arc<ConfigFile>,
     requested_build_dir: Option<PathBuf>,
+    block_device: RwLock<Option<PathBuf>>,
 }
 
 impl PathManager {
@@ -16,6 +17,7 @@ impl PathManager {
         Self {
             config,
             requested_build_dir: request.map(|i| i.as_ref().to_owned()),
+            block_device: RwLock::new(None),
         }
     }
 
@@ -41,6 +43,50 @@ impl PathManager {
             .unwrap_or(current_dir().expect("Failed acquire cwd").join("build"))
     }
 
+    /// Held by the running build to claim the build directory. Contains the PID of its owner
+    pub fn lock_file(&self) -> PathBuf {
+        self.build_dir().join("build.lock")
+    }
+
+    /// The socket over which the storage daemon is controlled
+    pub fn monitor_socket(&self) -> PathBuf {
+        self.build_dir().join("qemu-monitor.sock")
+    }
+
+    /// Where the storage daemon records its PID, so it can be found if the build dies
+    pub fn daemon_pid_file(&self) -> PathBuf {
+        self.build_dir().join("qemu-storage-daemon.pid")
+    }
+
+    /// The socket on which the storage daemon serves the image over NBD
+    pub fn nbd_socket(&self) -> PathBuf {
+        self.build_dir().join("nbd.sock")
+    }
+
+    /// Where the artifacts emitted by components are collected, organised by component
+    pub fn artifacts(&self) -> PathBuf {
+        self.build_dir().join("artifacts")
+    }
+
+    /// Resolves an artifact reference of the form `component::artifact` (optionally prefixed with `art://`) to its
+    /// location in the artifact store. The artifact may not exist yet.
+    pub fn artifact<Artifact: AsRef<str>>(&self, artifact: Artifact) -> Option<PathBuf> {
+        let artifact = artifact.as_ref();
+        let (component, artifact) = artifact.strip_prefix("art://").unwrap_or(artifact).split_once("::")?;
+
+        Some(self.artifacts().join(component).join(artifact))
+    }
+
+    /// Where the files of an initfs component are staged before they're packed
+    pub fn initfs<Component: AsRef<str>>(&self, component: Component) -> PathBuf {
+        self.build_dir().join("initfs").join(component.as_ref())
+    }
+
+    /// Where archives are unpacked while their contents are placed, so they needn't be held in memory
+    pub fn archives(&self) -> PathBuf {
+        self.build_dir().join("archives")
+    }
+
     /// The path where the PartFS filesystem is mounted - contains the raw partitions of the final image
     pub fn partitions(&self) -> PathBuf {
         self.build_dir().join("partitions")
@@ -48,12 +94,24 @@ impl PathManager {
 
     /// Get the path to a particular partition's block device
     pub fn partition<Part: AsRef<str>>(&self, partition: Part) -> Option<PathBuf> {
+        let block_device = self.block_device.read().expect("Block device lock poisoned");
+
         self.config
             .image
             .partitions
             .iter()
-            .find(|part| part.label.eq(partition.as_ref()))
-            .map(|part| self.partitions().join(&part.label))
+            .enumerate()
+            .find(|(_, part)| part.label.eq(partition.as_ref()))
+            .map(|(index, part)| match block_device.as_ref() {
+                Some(dev) => PathBuf::from(format!("{}p{}", dev.to_string_lossy(), index + 1)),
+                None => self.partitions().join(&part.label),
+            })
+    }
+
+    /// Route partitions to the kernel's partition nodes of a block device (eg. `/dev/loop0p1`) instead of PartitionFS.
+    /// Passing `None` reverts to PartitionFS.
+    pub fn set_block_device(&self, device: Option<PathBuf>) {
+        *self.block_device.write().expect("Block device lock poisoned") = device;
     }
 
     /// The path containing the mounted filesystems per partition
@@ -0,0 +1,73 @@
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use log::debug;
+
+use crate::cancel;
+use crate::error::*;
+use crate::error::global::Inner;
+use crate::paths::PathManager;
+
+/// An argument passed to a function defined in a shell script
+#[derive(Debug, Clone)]
+pub enum Arg {
+    Path(PathBuf),
+    String(String),
+    /// Passed as a NuShell `filesize` in bytes
+    Filesize(u64),
+}
+
+/// Calls a function defined in a NuShell script and returns everything it printed.
+/// Paths and strings are handed over through the environment, so they never need to be quoted.
+pub fn call(paths: &PathManager, script: &str, function: &str, args: &[Arg]) -> Result<String> {
+    Ok(String::from_utf8(call_raw(paths, script, function, args)?)?)
+}
+
+/// Like `call`, for functions whose output isn't necessarily text
+pub fn call_raw(paths: &PathManager, script: &str, function: &str, args: &[Arg]) -> Result<Vec<u8>> {
+    let mut command = Command::new("nu");
+    let mut call = function.to_owned();
+
+    for (index, arg) in args.iter().enumerate() {
+        let var = format!("REDOX_BUILDER_ARG_{}", index);
+
+        match arg {
+            Arg::Path(path) => {
+                command.env(&var, path);
+                call.push_str(&format!(" $env.{}", var));
+            }
+            Arg::String(string) => {
+                command.env(&var, string);
+                call.push_str(&format!(" $env.{}", var));
+            }
+            Arg::Filesize(size) => call.push_str(&format!(" {}b", size)),
+        }
+    }
+
+    debug!("Calling shell function: {}", &call);
+
+    command
+        .arg("--no-config-file")
+        .arg("-c")
+        .arg(format!("{}\n{}", script, call))
+        .env("artifacts", paths.artifacts())
+        .env("build_dir", paths.build_dir())
+        .env("image", paths.final_image())
+        .env("partition", paths.partitions())
+        .env("live", paths.live())
+        .stdout(Stdio::piped());
+
+    let output = match cancel::output(&mut command) {
+        Err(err) if matches!(err.inner(), Inner::IoError(io) if io.kind() == ErrorKind::NotFound) => {
+            return Err(BuildError::HostToolMissing("nu".into()).into());
+        }
+        output => output?,
+    };
+
+    if !output.status.success() {
+        return Err(BuildError::ShellFailed(function.to_owned(), output.status).into());
+    }
+
+    Ok(output.stdout)
+}
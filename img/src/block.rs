@@ -0,0 +1,239 @@
+use std::fs::File;
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::sync::Arc;
+
+use hub::error::*;
+use hub::global::Inner;
+
+/// Positional access to a disk or a region of one.
+/// Backends which can't expose partitions as files (eg. NBD) hand these out instead.
+pub trait BlockDevice: Send + Sync {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize>;
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize>;
+    fn flush(&self) -> Result<()>;
+    fn size(&self) -> Result<u64>;
+
+    fn read_exact_at(&self, mut offset: u64, mut buf: &mut [u8]) -> Result<()> {
+        while !buf.is_empty() {
+            match self.read_at(offset, buf)? {
+                0 => return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+                read => {
+                    offset += read as u64;
+                    buf = &mut buf[read..];
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_all_at(&self, mut offset: u64, mut buf: &[u8]) -> Result<()> {
+        while !buf.is_empty() {
+            match self.write_at(offset, buf)? {
+                0 => return Err(std::io::Error::from(std::io::ErrorKind::WriteZero).into()),
+                written => {
+                    offset += written as u64;
+                    buf = &buf[written..];
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl BlockDevice for File {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        Ok(FileExt::read_at(self, buf, offset)?)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize> {
+        Ok(FileExt::write_at(self, buf, offset)?)
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(self.sync_data()?)
+    }
+
+    // Block devices report a length of 0 in their metadata, so seek to the end instead
+    fn size(&self) -> Result<u64> {
+        let mut file = self;
+        Ok(file.seek(SeekFrom::End(0))?)
+    }
+}
+
+/// A window onto a region of another device, such as a partition on a disk.
+/// Accesses beyond the end of the window are cut short.
+pub struct Slice {
+    device: Arc<dyn BlockDevice>,
+    offset: u64,
+    len: u64,
+}
+
+impl Slice {
+    pub fn new(device: Arc<dyn BlockDevice>, offset: u64, len: u64) -> Self {
+        Self { device, offset, len }
+    }
+
+    fn clamp(&self, offset: u64, len: usize) -> usize {
+        self.len.saturating_sub(offset).min(len as u64) as usize
+    }
+}
+
+impl BlockDevice for Slice {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let len = self.clamp(offset, buf.len());
+        if len == 0 {
+            return Ok(0);
+        }
+
+        self.device.read_at(self.offset + offset, &mut buf[..len])
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize> {
+        let len = self.clamp(offset, buf.len());
+        if len == 0 && !buf.is_empty() {
+            return Err(std::io::Error::from_raw_os_error(libc::ENOSPC).into());
+        }
+
+        self.device.write_at(self.offset + offset, &buf[..len])
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.device.flush()
+    }
+
+    fn size(&self) -> Result<u64> {
+        Ok(self.len)
+    }
+}
+
+/// Presents a block device as a seekable stream, for libraries which expect `Read + Write + Seek`
+pub struct Stream {
+    device: Arc<dyn BlockDevice>,
+    position: u64,
+}
+
+impl Stream {
+    pub fn new(device: Arc<dyn BlockDevice>) -> Self {
+        Self { device, position: 0 }
+    }
+
+    fn io_err(err: Error) -> std::io::Error {
+        match err.into_inner() {
+            Inner::IoError(err) => err,
+            err => std::io::Error::other(err.to_string()),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.device.read_at(self.position, buf).map_err(Self::io_err)?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.device.write_at(self.position, buf).map_err(Self::io_err)?;
+        self.position += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.device.flush().map_err(Self::io_err)
+    }
+}
+
+impl Seek for Stream {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.device.size().map_err(Self::io_err)?.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+
+        self.position = position.ok_or(std::io::Error::from(ErrorKind::InvalidInput))?;
+        Ok(self.position)
+    }
+}
+
+/// Allows RedoxFS to operate on any block device rather than only files
+pub struct RedoxDisk(pub Box<dyn BlockDevice>);
+
+impl RedoxDisk {
+    fn syscall_err(err: Error) -> syscall::error::Error {
+        match err.into_inner() {
+            Inner::IoError(err) => syscall::error::Error::new(err.raw_os_error().unwrap_or(syscall::error::EIO)),
+            Inner::Syscall(err) => err,
+            _ => syscall::error::Error::new(syscall::error::EIO),
+        }
+    }
+}
+
+impl redoxfs::Disk for RedoxDisk {
+    unsafe fn read_at(&mut self, block: u64, buffer: &mut [u8]) -> syscall::error::Result<usize> {
+        self.0
+            .read_exact_at(block * redoxfs::BLOCK_SIZE, buffer)
+            .map(|_| buffer.len())
+            .map_err(Self::syscall_err)
+    }
+
+    unsafe fn write_at(&mut self, block: u64, buffer: &[u8]) -> syscall::error::Result<usize> {
+        self.0
+            .write_all_at(block * redoxfs::BLOCK_SIZE, buffer)
+            .map(|_| buffer.len())
+            .map_err(Self::syscall_err)
+    }
+
+    fn size(&mut self) -> syscall::error::Result<u64> {
+        self.0.size().map_err(Self::syscall_err)
+    }
+}
+
+/// A disk held in memory, for tests which need a filesystem but not a file to keep it in
+#[cfg(test)]
+#[derive(Clone)]
+pub(crate) struct Memory(Arc<std::sync::Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl Memory {
+    pub fn new(size: usize) -> Self {
+        Self(Arc::new(std::sync::Mutex::new(vec![0; size])))
+    }
+}
+
+#[cfg(test)]
+impl BlockDevice for Memory {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let disk = self.0.lock().expect("Disk lock poisoned");
+        let data = disk.get(offset as usize..).unwrap_or_default();
+        let len = data.len().min(buf.len());
+
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize> {
+        let mut disk = self.0.lock().expect("Disk lock poisoned");
+        let data = disk.get_mut(offset as usize..).unwrap_or_default();
+        let len = data.len().min(buf.len());
+        if len == 0 && !buf.is_empty() {
+            return Err(std::io::Error::from_raw_os_error(libc::ENOSPC).into());
+        }
+
+        data[..len].copy_from_slice(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn size(&self) -> Result<u64> {
+        Ok(self.0.lock().expect("Disk lock poisoned").len() as u64)
+    }
+}
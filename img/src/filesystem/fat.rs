@@ -0,0 +1,194 @@
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use fatfs::{Date, DateTime, Dir, FatType, FileSystem, FormatVolumeOptions, FsOptions, Time};
+use log::info;
+use time::OffsetDateTime;
+
+use hub::error::*;
+
+use crate::block::{BlockDevice, Stream};
+use crate::filesystem::{components, FilesystemDriver, Metadata, Target, Usage};
+use crate::mnt::{Mount, MountKind};
+
+/// FAT12, FAT16 or FAT32, created and populated in-process. The partition is never mounted on the host.
+pub struct Fat {
+    name: &'static str,
+    /// If unset, the FAT type is chosen based on the size of the partition
+    fat_type: Option<FatType>,
+}
+
+impl Fat {
+    pub const AUTO: Self = Self { name: "fat", fat_type: None };
+    pub const FAT12: Self = Self { name: "fat12", fat_type: Some(FatType::Fat12) };
+    pub const FAT16: Self = Self { name: "fat16", fat_type: Some(FatType::Fat16) };
+    pub const FAT32: Self = Self { name: "fat32", fat_type: Some(FatType::Fat32) };
+
+    /// The filesystem opened by `mount`
+    fn fs<'a>(&self, mount: &'a Mount) -> Result<MutexGuard<'a, FileSystem<Stream>>> {
+        let MountKind::Fat { fs, .. } = mount.kind() else {
+            return Err(BuildError::InvalidDiskType.into());
+        };
+
+        Ok(fs.lock().expect("FAT lock poisoned"))
+    }
+}
+
+/// FAT volume labels are at most 11 characters, padded with spaces
+fn volume_label(label: &str) -> [u8; 11] {
+    let mut volume_label = [b' '; 11];
+
+    for (byte, char) in volume_label.iter_mut().zip(label.chars().filter(char::is_ascii)) {
+        *byte = char.to_ascii_uppercase() as u8;
+    }
+
+    volume_label
+}
+
+/// 1980-01-01 00:00:00 UTC. FAT can't represent anything earlier
+const FAT_EPOCH: i64 = 315_532_800;
+
+/// Converts a UNIX timestamp to the calendar time stored by FAT, in UTC
+fn date_time(secs: u64) -> DateTime {
+    let time = OffsetDateTime::from_unix_timestamp((secs as i64).max(FAT_EPOCH)).unwrap_or(OffsetDateTime::UNIX_EPOCH);
+
+    DateTime {
+        date: Date {
+            year: time.year() as u16,
+            month: time.month() as u16,
+            day: time.day() as u16,
+        },
+        time: Time {
+            hour: time.hour() as u16,
+            min: time.minute() as u16,
+            sec: time.second() as u16,
+            millis: 0,
+        },
+    }
+}
+
+impl FilesystemDriver for Fat {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn create(&self, target: Target) -> Result<()> {
+        info!("Creating {} filesystem on '{}'", self.name.to_uppercase(), &target.partition.label);
+
+        let mut options = FormatVolumeOptions::new().volume_label(volume_label(&target.partition.label));
+        if let Some(fat_type) = self.fat_type {
+            options = options.fat_type(fat_type);
+        }
+
+        let device = Arc::<dyn BlockDevice>::from(target.device);
+        fatfs::format_volume(Stream::new(Arc::clone(&device)), options)
+            .map_err(|err| BuildError::FailedToCreateFilesystem(format!("{}: {}", self.name, err)))?;
+
+        device.flush()
+    }
+
+    fn mount(&self, target: Target, _mount_point: &Path) -> Result<MountKind> {
+        let device = Arc::<dyn BlockDevice>::from(target.device);
+        let fs = FileSystem::new(Stream::new(Arc::clone(&device)), FsOptions::new())?;
+
+        Ok(MountKind::Fat { fs: Mutex::new(fs), device })
+    }
+
+    fn stat(&self, mount: &Mount) -> Result<Usage> {
+        let stats = self.fs(mount)?.stats()?;
+
+        Ok(Usage {
+            capacity: stats.total_clusters() as u64 * stats.cluster_size() as u64,
+            free: stats.free_clusters() as u64 * stats.cluster_size() as u64,
+        })
+    }
+
+    fn write_file(&self, mount: &Mount, path: &Path, contents: &[u8]) -> Result<()> {
+        let components = components(path)?;
+        let Some((name, parents)) = components.split_last() else {
+            return Err(BuildError::InvalidFilePath(path.to_owned()).into());
+        };
+
+        let fs = self.fs(mount)?;
+
+        let mut dir = fs.root_dir();
+        for parent in parents {
+            dir = dir.create_dir(parent)?;
+        }
+
+        let mut file = dir.create_file(name)?;
+        file.truncate()?;
+        file.write_all(contents)?;
+        Ok(file.flush()?)
+    }
+
+    fn create_dir(&self, mount: &Mount, path: &Path) -> Result<()> {
+        let fs = self.fs(mount)?;
+
+        let mut dir = fs.root_dir();
+        for name in components(path)? {
+            dir = dir.create_dir(name)?;
+        }
+
+        Ok(())
+    }
+
+    fn read_file(&self, mount: &Mount, path: &Path) -> Result<Vec<u8>> {
+        let fs = self.fs(mount)?;
+        let mut contents = vec![];
+
+        fs.root_dir().open_file(&components(path)?.join("/"))?.read_to_end(&mut contents)?;
+
+        Ok(contents)
+    }
+
+    /// FAT has neither ownership nor Unix permissions, so only the modification time is kept. The root directory has
+    /// no timestamps at all.
+    #[allow(deprecated)] // fatfs would rather times came from a `TimeProvider`, which can't differ between files
+    /// FAT has no permissions or owners, so only the modification time is kept. fatfs can only set that of files, so
+    /// directories keep the time they were created at.
+    fn set_metadata(&self, mount: &Mount, path: &Path, metadata: &Metadata) -> Result<()> {
+        let Some((secs, _)) = metadata.mtime else {
+            return Ok(());
+        };
+
+        let components = components(path)?;
+        let Some((name, parents)) = components.split_last() else {
+            return Ok(());
+        };
+
+        let fs = self.fs(mount)?;
+
+        let mut dir = fs.root_dir();
+        for parent in parents {
+            dir = dir.open_dir(parent)?;
+        }
+
+        let entry = find(&dir, name)?;
+        if entry.is_file() {
+            let mut file = entry.to_file();
+            file.set_modified(date_time(secs));
+            file.flush()?;
+        }
+
+        Ok(())
+    }
+
+    fn symlink(&self, _mount: &Mount, path: &Path, _target: &Path) -> Result<()> {
+        Err(BuildError::UnsupportedOperation(self.name.to_owned(), format!("symlink {:?}", path)).into())
+    }
+}
+
+/// Looks up an entry of a directory by its long or short name, ignoring case as FAT does
+fn find<'a>(dir: &Dir<'a, Stream>, name: &str) -> Result<fatfs::DirEntry<'a, Stream>> {
+    for entry in dir.iter() {
+        let entry = entry?;
+
+        if entry.file_name().eq_ignore_ascii_case(name) || entry.short_file_name().eq_ignore_ascii_case(name) {
+            return Ok(entry);
+        }
+    }
+
+    Err(std::io::Error::from(std::io::ErrorKind::NotFound).into())
+}
@@ -0,0 +1,24 @@
+use std::path::Path;
+
+use hub::error::*;
+
+use crate::filesystem::{FilesystemDriver, Target};
+use crate::mnt::MountKind;
+
+/// A plain directory on the host, used to stage files before they're packed into an archive.
+/// It can't be selected as a partition's filesystem, so it's never created or mounted.
+pub struct HostDirectory;
+
+impl FilesystemDriver for HostDirectory {
+    fn name(&self) -> &str {
+        "host"
+    }
+
+    fn create(&self, target: Target) -> Result<()> {
+        Err(BuildError::UnsupportedOperation(self.name().to_owned(), format!("create on '{}'", &target.partition.label)).into())
+    }
+
+    fn mount(&self, target: Target, _mount_point: &Path) -> Result<MountKind> {
+        Err(BuildError::UnsupportedOperation(self.name().to_owned(), format!("mount '{}'", &target.partition.label)).into())
+    }
+}
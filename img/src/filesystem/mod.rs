@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use log::warn;
+
+use hub::config::{ConfigFile, Partition};
+use hub::error::*;
+use hub::paths::PathManager;
+
+use crate::block::BlockDevice;
+use crate::mnt::{Mount, MountKind};
+use crate::place::default_mtime;
+
+pub use self::fat::Fat;
+pub use self::host::HostDirectory;
+pub use self::redox::RedoxFS;
+pub use self::shell::ShellFilesystem;
+
+mod fat;
+mod host;
+mod redox;
+mod shell;
+
+/// A partition on which a filesystem is about to be created or mounted
+pub struct Target<'a> {
+    pub partition: &'a Partition,
+    pub paths: &'a PathManager,
+    /// The partition's file or block device, if the disk manager exposes it through the filesystem
+    pub source: Option<PathBuf>,
+    /// Direct access to the partition, regardless of how the disk manager exposes it
+    pub device: Box<dyn BlockDevice>,
+}
+
+impl Target<'_> {
+    /// The partition's file or block device, for drivers relying on external tools
+    pub fn source(&self) -> Result<&Path> {
+        self.source
+            .as_deref()
+            .filter(|source| source.exists())
+            .ok_or(BuildError::NoPartitionMountPoint(self.partition.label.clone()).into())
+    }
+}
+
+/// Ownership, permissions and timestamps of a placed file. Anything unset is left as the driver created it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Metadata {
+    /// Permission bits including the setuid, setgid and sticky bits
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// Seconds and nanoseconds since the UNIX epoch
+    pub mtime: Option<(u64, u32)>,
+}
+
+/// The space taken up by a mounted filesystem, in bytes
+#[derive(Debug, Clone, Copy)]
+pub struct Usage {
+    pub capacity: u64,
+    pub free: u64,
+}
+
+/// Creates and operates a particular kind of filesystem. Partitions select a driver by setting `filesystem` to its name.
+pub trait FilesystemDriver: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Whether partitions with `encryption` set can use this filesystem
+    fn encryption(&self) -> bool {
+        false
+    }
+
+    /// Creates an empty filesystem spanning the whole partition
+    fn create(&self, target: Target) -> Result<()>;
+
+    /// Mounts the partition's filesystem onto `mount_point`, returning once it's ready to be used
+    fn mount(&self, target: Target, mount_point: &Path) -> Result<MountKind>;
+
+    /// Tears down a mount made by `mount`
+    fn unmount(&self, _source: Option<&Path>, target: &Path, kind: MountKind) -> Result<()> {
+        kind.release(target)
+    }
+
+    /// By default, this asks the kernel about the filesystem mounted at the mount's target
+    fn stat(&self, mount: &Mount) -> Result<Usage> {
+        let path = CString::new(mount.target.as_os_str().as_bytes())?;
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+
+        if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        Ok(Usage {
+            capacity: stat.f_blocks as u64 * stat.f_frsize as u64,
+            free: stat.f_bavail as u64 * stat.f_frsize as u64,
+        })
+    }
+
+    /// Places a file into the mounted filesystem, creating its parent directories. A symlink already at `path` is
+    /// replaced rather than written through. Paths are relative to the root of the filesystem.
+    fn write_file(&self, mount: &Mount, path: &Path, contents: &[u8]) -> Result<()> {
+        let path = host_path(mount, path)?;
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        if path.is_symlink() {
+            fs::remove_file(&path)?;
+        }
+
+        Ok(fs::write(path, contents)?)
+    }
+
+    /// Creates a directory along with its parents, succeeding if it already exists
+    fn create_dir(&self, mount: &Mount, path: &Path) -> Result<()> {
+        let host = host_path(mount, path)?;
+
+        if host.is_symlink() {
+            return Err(BuildError::InvalidFilePath(path.to_owned()).into());
+        }
+
+        Ok(fs::create_dir_all(host)?)
+    }
+
+    /// Creates a symbolic link at `path` pointing to `target`, creating its parent directories
+    fn symlink(&self, mount: &Mount, path: &Path, target: &Path) -> Result<()> {
+        let path = host_path(mount, path)?;
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        Ok(std::os::unix::fs::symlink(target, path)?)
+    }
+
+    /// Reads a file which has already been placed
+    fn read_file(&self, mount: &Mount, path: &Path) -> Result<Vec<u8>> {
+        let host = host_path(mount, path)?;
+
+        if host.is_symlink() {
+            return Err(BuildError::InvalidFilePath(path.to_owned()).into());
+        }
+
+        Ok(fs::read(host)?)
+    }
+
+    /// Applies ownership, permissions and timestamps to a placed file. Symbolic links themselves are changed rather
+    /// than what they point to.
+    fn set_metadata(&self, mount: &Mount, path: &Path, metadata: &Metadata) -> Result<()> {
+        let path = host_path(mount, path)?;
+        let cpath = CString::new(path.as_os_str().as_bytes())?;
+
+        // Permissions of a link can't be changed, only those of its target
+        if let Some(mode) = metadata.mode.filter(|_| !path.is_symlink()) {
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode))?;
+        }
+
+        if metadata.uid.is_some() || metadata.gid.is_some() {
+            std::os::unix::fs::lchown(&path, metadata.uid, metadata.gid)?;
+        }
+
+        if let Some((secs, nsecs)) = metadata.mtime {
+            let times = [
+                libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_OMIT },
+                libc::timespec { tv_sec: secs as libc::time_t, tv_nsec: nsecs as libc::c_long },
+            ];
+
+            if unsafe { libc::utimensat(libc::AT_FDCWD, cpath.as_ptr(), times.as_ptr(), libc::AT_SYMLINK_NOFOLLOW) } != 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Resolves a path within a filesystem mounted on the host. Symlinks placed into the filesystem point somewhere within
+/// the image rather than the host, so they're never followed. Otherwise a link such as `etc -> /etc` would lead anything
+/// placed below it out of the mount. Fails with `BuildError::InvalidFilePath` if any of the path's parents is a symlink.
+fn host_path(mount: &Mount, path: &Path) -> Result<PathBuf> {
+    let components = components(path)?;
+    let mut host = mount.target.clone();
+
+    for (index, name) in components.iter().enumerate() {
+        host.push(name);
+
+        if index + 1 < components.len() && host.is_symlink() {
+            return Err(BuildError::InvalidFilePath(path.to_owned()).into());
+        }
+    }
+
+    Ok(host)
+}
+
+/// Splits a path within a filesystem into the names leading to it, for drivers which walk directories themselves.
+/// Fails with `BuildError::InvalidFilePath` if the path refers to a parent directory or isn't valid UTF-8.
+pub(crate) fn components(path: &Path) -> Result<Vec<&str>> {
+    let invalid = || Error::from(BuildError::InvalidFilePath(path.to_owned()));
+
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_str().ok_or_else(invalid)),
+            Component::RootDir | Component::CurDir => None,
+            _ => Some(Err(invalid())),
+        })
+        .collect()
+}
+
+/// The filesystems available to a build, keyed by name. Names are case-insensitive.
+pub struct Registry {
+    drivers: HashMap<String, Arc<dyn FilesystemDriver>>,
+}
+
+impl Registry {
+    /// The built-in filesystems along with those defined in the config.
+    /// User-defined filesystems take precedence over built-in ones of the same name.
+    pub fn new(config: &ConfigFile, paths: Arc<PathManager>) -> Self {
+        let mut registry = Self { drivers: HashMap::new() };
+
+        registry.register(Arc::new(RedoxFS::new(default_mtime(&config.image))));
+        registry.register(Arc::new(Fat::AUTO));
+        registry.register(Arc::new(Fat::FAT12));
+        registry.register(Arc::new(Fat::FAT16));
+        registry.register(Arc::new(Fat::FAT32));
+
+        for filesystem in config.filesystems.iter() {
+            if registry.drivers.contains_key(&filesystem.name.to_lowercase()) {
+                warn!("Filesystem '{}' overrides the built-in driver", &filesystem.name);
+            }
+
+            registry.register(Arc::new(ShellFilesystem::new(filesystem.clone(), Arc::clone(&paths))));
+        }
+
+        registry
+    }
+
+    pub fn register(&mut self, driver: Arc<dyn FilesystemDriver>) {
+        self.drivers.insert(driver.name().to_lowercase(), driver);
+    }
+
+    /// Fails with `BuildError::UnrecognisedFilesystem` if no driver goes by this name
+    pub fn get(&self, name: &str) -> Result<Arc<dyn FilesystemDriver>> {
+        self.drivers
+            .get(&name.to_lowercase())
+            .cloned()
+            .ok_or(BuildError::UnrecognisedFilesystem(name.to_owned()).into())
+    }
+}
@@ -0,0 +1,378 @@
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+
+use log::info;
+use redoxfs::{FileSystem, Node, Transaction, TreeData, TreePtr, BLOCK_SIZE};
+
+use hub::config::{Encryption, Partition};
+use hub::error::*;
+use hub::global::Inner;
+
+use crate::block::RedoxDisk;
+use crate::filesystem::{components, FilesystemDriver, Metadata, Target, Usage};
+use crate::gpt;
+use crate::mnt::{Mount, MountKind};
+
+/// RedoxFS, created and populated in-process through its transaction API. The partition is never mounted on the host.
+pub struct RedoxFS {
+    /// Every timestamp the driver sets, as seconds and nanoseconds since the epoch. See `place::default_mtime`
+    time: (u64, u32),
+}
+
+impl RedoxFS {
+    pub fn new(time: (u64, u32)) -> Self {
+        Self { time }
+    }
+
+    /// Runs a transaction against the open filesystem. Changes are committed once it succeeds.
+    fn tx<T>(&self, mount: &Mount, f: impl FnOnce(&mut Transaction<RedoxDisk>) -> Result<T>) -> Result<T> {
+        let MountKind::RedoxFS(fs) = mount.kind() else {
+            return Err(BuildError::InvalidDiskType.into());
+        };
+
+        // A transaction can only fail with a syscall error, so any other error is carried out of it separately
+        let mut error = None;
+        let result = fs.lock().expect("RedoxFS lock poisoned").tx(|tx| {
+            f(tx).map_err(|err| match err.into_inner() {
+                Inner::Syscall(err) => err,
+                err => {
+                    error = Some(Error::from(err));
+                    syscall::error::Error::new(syscall::error::EINVAL)
+                }
+            })
+        });
+
+        match error {
+            Some(err) => Err(err),
+            None => Ok(result?),
+        }
+    }
+}
+
+/// The password of an encrypted partition
+fn password(partition: &Partition) -> Result<Option<Vec<u8>>> {
+    partition.encryption.as_ref().map(Encryption::password).transpose()
+}
+
+/// Looks up a node by name within a directory, creating it with the given mode and time if it doesn't exist
+fn find_or_create(
+    tx: &mut Transaction<RedoxDisk>, parent: TreePtr<Node>, name: &str, mode: u16, (ctime, ctime_nsec): (u64, u32),
+) -> Result<TreeData<Node>> {
+    match tx.find_node(parent, name) {
+        Err(err) if err.errno == syscall::error::ENOENT => Ok(tx.create_node(parent, name, mode, ctime, ctime_nsec)?),
+        node => Ok(node?),
+    }
+}
+
+/// Finds the node at a path. Like `parent_dir`, it refuses to descend into anything but a directory.
+fn lookup(tx: &mut Transaction<RedoxDisk>, path: &Path) -> Result<TreeData<Node>> {
+    let mut node = tx.read_tree(TreePtr::root())?;
+
+    for name in components(path)? {
+        if kind(&node) != Node::MODE_DIR {
+            return Err(BuildError::InvalidFilePath(path.to_owned()).into());
+        }
+
+        node = tx.find_node(node.ptr(), name)?;
+    }
+
+    Ok(node)
+}
+
+/// Walks to the directory containing a path, creating any which are missing along the way.
+/// Symlinks and files on the way are an error rather than being treated as directories, which would corrupt them.
+fn parent_dir(tx: &mut Transaction<RedoxDisk>, path: &Path, parents: &[&str], time: (u64, u32)) -> Result<TreePtr<Node>> {
+    let mut dir = TreePtr::root();
+
+    for name in parents {
+        let node = find_or_create(tx, dir, name, Node::MODE_DIR | 0o755, time)?;
+        if kind(&node) != Node::MODE_DIR {
+            return Err(BuildError::InvalidFilePath(path.to_owned()).into());
+        }
+
+        dir = node.ptr();
+    }
+
+    Ok(dir)
+}
+
+/// The type bits of a node's mode, eg. `Node::MODE_DIR`
+fn kind(node: &TreeData<Node>) -> u16 {
+    node.data().mode() & Node::MODE_TYPE
+}
+
+impl FilesystemDriver for RedoxFS {
+    fn name(&self) -> &str {
+        "redoxfs"
+    }
+
+    fn encryption(&self) -> bool {
+        true
+    }
+
+    fn create(&self, target: Target) -> Result<()> {
+        info!("Creating RedoxFS filesystem on '{}'", &target.partition.label);
+
+        let options = &target.partition.redoxfs;
+        let password = password(target.partition)?;
+
+        let reserved = match options.reserved.as_ref() {
+            Some(artifact) => {
+                let path = target
+                    .paths
+                    .artifact(artifact)
+                    .filter(|path| path.is_file())
+                    .ok_or(BuildError::MissingArtifact(artifact.clone()))?;
+
+                fs::read(path)?
+            }
+            None => vec![],
+        };
+
+        let (ctime, ctime_nsec) = match options.ctime {
+            Some(ms) => (ms / 1000, (ms % 1000) as u32 * 1_000_000),
+            None => self.time,
+        };
+
+        let mut fs = FileSystem::create_reserved(
+            RedoxDisk(target.device),
+            password.as_deref(),
+            &reserved,
+            ctime,
+            ctime_nsec,
+        )?;
+
+        if let Some(uuid) = options.uuid.as_ref() {
+            // RedoxFS stores UUIDs in the order they're written rather than mixed-endian like GPT
+            let uuid = gpt::parse_uuid(uuid).ok_or(BuildError::InvalidUuid(uuid.clone()))?;
+
+            fs.tx(|tx| {
+                tx.header.uuid = uuid;
+                tx.header_changed = true;
+                Ok(())
+            })?;
+        }
+
+        Ok(())
+    }
+
+    fn mount(&self, target: Target, _mount_point: &Path) -> Result<MountKind> {
+        let password = password(target.partition)?;
+
+        let fs = FileSystem::open(RedoxDisk(target.device), password.as_deref(), None, false)?;
+
+        Ok(MountKind::RedoxFS(Mutex::new(fs)))
+    }
+
+    fn stat(&self, mount: &Mount) -> Result<Usage> {
+        let MountKind::RedoxFS(fs) = mount.kind() else {
+            return Err(BuildError::InvalidDiskType.into());
+        };
+
+        let fs = fs.lock().expect("RedoxFS lock poisoned");
+
+        Ok(Usage {
+            capacity: fs.header.size(),
+            free: fs.allocator().free() * BLOCK_SIZE,
+        })
+    }
+
+    fn write_file(&self, mount: &Mount, path: &Path, contents: &[u8]) -> Result<()> {
+        let components = components(path)?;
+        let Some((name, parents)) = components.split_last() else {
+            return Err(BuildError::InvalidFilePath(path.to_owned()).into());
+        };
+
+        self.tx(mount, |tx| {
+            let dir = parent_dir(tx, path, parents, self.time)?;
+
+            // A symlink is replaced rather than written through, which would turn the contents into its target
+            match tx.find_node(dir, name) {
+                Ok(node) if kind(&node) == Node::MODE_SYMLINK => {
+                    tx.remove_node(dir, name, Node::MODE_SYMLINK)?;
+                }
+                Ok(node) if kind(&node) == Node::MODE_DIR => {
+                    return Err(BuildError::InvalidFilePath(path.to_owned()).into());
+                }
+                _ => {}
+            }
+
+            let node = find_or_create(tx, dir, name, Node::MODE_FILE | 0o644, self.time)?.ptr();
+
+            let (mtime, mtime_nsec) = self.time;
+            tx.truncate_node(node, 0, mtime, mtime_nsec)?;
+            tx.write_node(node, 0, contents, mtime, mtime_nsec)?;
+
+            Ok(())
+        })
+    }
+
+    fn create_dir(&self, mount: &Mount, path: &Path) -> Result<()> {
+        let components = components(path)?;
+
+        self.tx(mount, |tx| parent_dir(tx, path, &components, self.time).map(|_| ()))
+    }
+
+    fn read_file(&self, mount: &Mount, path: &Path) -> Result<Vec<u8>> {
+        self.tx(mount, |tx| {
+            let node = lookup(tx, path)?;
+            let mut contents = vec![0; node.data().size() as usize];
+
+            let (atime, atime_nsec) = self.time;
+            let mut read = 0;
+            while read < contents.len() {
+                match tx.read_node(node.ptr(), read as u64, &mut contents[read..], atime, atime_nsec)? {
+                    0 => break,
+                    len => read += len,
+                }
+            }
+
+            contents.truncate(read);
+            Ok(contents)
+        })
+    }
+
+    fn set_metadata(&self, mount: &Mount, path: &Path, metadata: &Metadata) -> Result<()> {
+        self.tx(mount, |tx| {
+            let mut node = lookup(tx, path)?;
+
+            if let Some(mode) = metadata.mode {
+                let mode = (node.data().mode() & Node::MODE_TYPE) | (mode as u16 & Node::MODE_PERM);
+                node.data_mut().set_mode(mode);
+            }
+
+            if let Some(uid) = metadata.uid {
+                node.data_mut().set_uid(uid);
+            }
+
+            if let Some(gid) = metadata.gid {
+                node.data_mut().set_gid(gid);
+            }
+
+            if let Some((mtime, mtime_nsec)) = metadata.mtime {
+                node.data_mut().set_mtime(mtime, mtime_nsec);
+            }
+
+            Ok(tx.sync_tree(node)?)
+        })
+    }
+
+    fn symlink(&self, mount: &Mount, path: &Path, target: &Path) -> Result<()> {
+        let components = components(path)?;
+        let Some((name, parents)) = components.split_last() else {
+            return Err(BuildError::InvalidFilePath(path.to_owned()).into());
+        };
+
+        let target = target.to_str().ok_or(BuildError::InvalidFilePath(target.to_owned()))?;
+
+        // RedoxFS stores the target of a link as its contents
+        self.tx(mount, |tx| {
+            let dir = parent_dir(tx, path, parents, self.time)?;
+            let (ctime, ctime_nsec) = self.time;
+            let node = tx.create_node(dir, name, Node::MODE_SYMLINK | 0o777, ctime, ctime_nsec)?.ptr();
+            tx.write_node(node, 0, target.as_bytes(), ctime, ctime_nsec)?;
+
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use hub::config::ConfigFile;
+    use hub::paths::PathManager;
+
+    use crate::block::Memory;
+
+    use super::*;
+
+    const FS: RedoxFS = RedoxFS { time: (1_700_000_000, 0) };
+
+    /// A new RedoxFS held in memory, mounted so files can be placed into it
+    fn mounted() -> Mount {
+        let config: ConfigFile = serde_json::from_str(r#"{ "name": "test", "image": { "label": "test", "size": 16 } }"#).unwrap();
+        let partition: Partition = serde_json::from_str(r#"{ "label": "root", "size": 16, "filesystem": "redoxfs" }"#).unwrap();
+        let paths = PathManager::new(Arc::new(config), Some(std::env::temp_dir().join("redox-builder-redoxfs")));
+
+        let disk = Memory::new(16 * 1024 * 1024);
+        let target = || Target { partition: &partition, paths: &paths, source: None, device: Box::new(disk.clone()) };
+
+        FS.create(target()).unwrap();
+        let kind = FS.mount(target(), Path::new("/")).unwrap();
+
+        Mount::new(partition.label.clone(), None, PathBuf::from("/"), Arc::new(FS), kind)
+    }
+
+    /// The type of the node at a path
+    fn node_kind(mount: &Mount, path: &str) -> u16 {
+        FS.tx(mount, |tx| lookup(tx, Path::new(path)).map(|node| kind(&node))).unwrap()
+    }
+
+    #[test]
+    fn files_are_overwritten() {
+        let mount = mounted();
+        FS.write_file(&mount, Path::new("/etc/hostname"), b"redox-builder").unwrap();
+        FS.write_file(&mount, Path::new("/etc/hostname"), b"redox").unwrap();
+
+        assert_eq!(node_kind(&mount, "/etc"), Node::MODE_DIR);
+        assert_eq!(FS.read_file(&mount, Path::new("/etc/hostname")).unwrap(), b"redox");
+    }
+
+    #[test]
+    fn symlinks_are_replaced_rather_than_written_through() {
+        let mount = mounted();
+        FS.symlink(&mount, Path::new("/etc/motd"), Path::new("/usr/share/motd")).unwrap();
+        FS.write_file(&mount, Path::new("/etc/motd"), b"hello").unwrap();
+
+        assert_eq!(node_kind(&mount, "/etc/motd"), Node::MODE_FILE);
+        assert_eq!(FS.read_file(&mount, Path::new("/etc/motd")).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn symlinks_and_files_are_not_descended_into() {
+        let mount = mounted();
+        FS.symlink(&mount, Path::new("/lib"), Path::new("usr/lib")).unwrap();
+        FS.write_file(&mount, Path::new("/etc"), b"hello").unwrap();
+
+        for path in ["/lib/libc.so", "/lib/x86_64/libc.so", "/etc/hostname"] {
+            let result = FS.write_file(&mount, Path::new(path), b"hello");
+            assert!(matches!(result.unwrap_err().inner(), Inner::BuildError(BuildError::InvalidFilePath(_))), "{}", path);
+        }
+
+        let result = FS.create_dir(&mount, Path::new("/lib/x86_64"));
+        assert!(matches!(result.unwrap_err().inner(), Inner::BuildError(BuildError::InvalidFilePath(_))));
+
+        let result = FS.read_file(&mount, Path::new("/etc/hostname"));
+        assert!(matches!(result.unwrap_err().inner(), Inner::BuildError(BuildError::InvalidFilePath(_))));
+
+        assert_eq!(node_kind(&mount, "/lib"), Node::MODE_SYMLINK);
+        assert_eq!(FS.read_file(&mount, Path::new("/etc")).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn timestamps_are_those_of_the_build() {
+        let mount = mounted();
+        FS.write_file(&mount, Path::new("/usr/bin/ion"), b"shell").unwrap();
+        FS.symlink(&mount, Path::new("/bin"), Path::new("usr/bin")).unwrap();
+        FS.read_file(&mount, Path::new("/usr/bin/ion")).unwrap();
+
+        for path in ["/", "/usr", "/usr/bin", "/usr/bin/ion", "/bin"] {
+            let mtime = FS.tx(&mount, |tx| Ok(lookup(tx, Path::new(path))?.data().mtime())).unwrap();
+            assert_eq!(mtime, FS.time, "{}", path);
+        }
+    }
+
+    #[test]
+    fn directories_are_not_overwritten() {
+        let mount = mounted();
+        FS.create_dir(&mount, Path::new("/etc")).unwrap();
+        let result = FS.write_file(&mount, Path::new("/etc"), b"hello");
+
+        assert!(matches!(result.unwrap_err().inner(), Inner::BuildError(BuildError::InvalidFilePath(_))));
+        assert_eq!(node_kind(&mount, "/etc"), Node::MODE_DIR);
+    }
+}
@@ -0,0 +1,77 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use log::info;
+use serde_json::Value;
+
+use hub::config::Filesystem;
+use hub::error::*;
+use hub::paths::PathManager;
+use hub::shell::{call, Arg};
+
+use crate::filesystem::{FilesystemDriver, Target, Usage};
+use crate::mnt::{Mount, MountKind};
+
+/// A filesystem defined in the config, operated by the `create`, `mount`, `umount` and `stat` functions of its script
+pub struct ShellFilesystem {
+    filesystem: Filesystem,
+    paths: Arc<PathManager>,
+}
+
+impl ShellFilesystem {
+    pub fn new(filesystem: Filesystem, paths: Arc<PathManager>) -> Self {
+        Self { filesystem, paths }
+    }
+
+    fn call(&self, function: &str, args: &[Arg]) -> Result<String> {
+        call(&self.paths, &self.filesystem.shell, function, args)
+    }
+}
+
+impl FilesystemDriver for ShellFilesystem {
+    fn name(&self) -> &str {
+        &self.filesystem.name
+    }
+
+    fn create(&self, target: Target) -> Result<()> {
+        info!("Creating {} filesystem on '{}'", &self.filesystem.name, &target.partition.label);
+
+        self.call("create", &[
+            Arg::Path(target.source()?.to_owned()),
+            Arg::Filesize(target.device.size()?),
+        ])?;
+
+        Ok(())
+    }
+
+    fn mount(&self, target: Target, mount_point: &Path) -> Result<MountKind> {
+        let source = target.source()?;
+        info!("Mounting {}: {:?} => {:?}", &self.filesystem.name, source, mount_point);
+
+        self.call("mount", &[Arg::Path(source.to_owned()), Arg::Path(mount_point.to_owned())])?;
+
+        Ok(MountKind::External)
+    }
+
+    // Mounting fails without a source, so there's always one by the time the filesystem is unmounted
+    fn unmount(&self, source: Option<&Path>, target: &Path, _kind: MountKind) -> Result<()> {
+        let source = source.ok_or(BuildError::InvalidFilePath(target.to_owned()))?;
+        self.call("umount", &[Arg::Path(source.to_owned()), Arg::Path(target.to_owned())])?;
+
+        Ok(())
+    }
+
+    fn stat(&self, mount: &Mount) -> Result<Usage> {
+        let source = mount.source.clone().ok_or(BuildError::NoPartitionMountPoint(mount.label.clone()))?;
+        let output = self.call("stat", &[Arg::Path(source)])?;
+        let usage: Value = serde_json::from_str(&output)?;
+
+        match (usage["capacity"].as_f64(), usage["free"].as_f64()) {
+            (Some(capacity), Some(free)) => Ok(Usage {
+                capacity: capacity as u64,
+                free: free as u64,
+            }),
+            _ => Err(BuildError::InvalidShellOutput("stat".into(), output).into()),
+        }
+    }
+}
@@ -1,12 +1,13 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::ffi::{OsStr, OsString};
 use std::fs::{File, OpenOptions};
-use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
-use std::sync::Arc;
+use std::io::ErrorKind;
+use std::os::unix::fs::FileExt;
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
 use std::time::{Duration, SystemTime};
 
-use bufreaderwriter::rand::BufReaderWriterRand;
-use fuser::{BackgroundSession, KernelConfig, mount2, MountOption, ReplyData, ReplyEmpty, ReplyIoctl, ReplyOpen, ReplyStatfs, ReplyWrite};
+use fuser::{BackgroundSession, consts, KernelConfig, MountOption, ReplyData, ReplyEmpty, ReplyIoctl, ReplyOpen, ReplyStatfs, ReplyWrite, TimeOrNow};
 use fuser::FileAttr;
 use fuser::Filesystem;
 use fuser::FileType;
@@ -17,13 +18,15 @@ use fuser::Request;
 use fuser::spawn_mount2;
 use libc::c_int;
 use libparted::{Device, Disk, Partition};
-use log::{debug, error, info, trace};
+use log::{debug, error, info, trace, warn};
+use serde_json::json;
 
 use hub::error::*;
 use hub::global::Inner;
 use hub::paths::PathManager;
 
 use crate::DiskManager;
+use crate::gpt;
 
 #[link(name = "c")]
 extern "C" {
@@ -31,23 +34,33 @@ extern "C" {
     fn getegid() -> u32;
 }
 
-const MIN_BLOCK_SIZE: u64 = 4096u64.pow(2); // 4096 KiB (4MiB)
-const MAX_BLOCK_SIZE: u64 = MIN_BLOCK_SIZE.pow(2);
+/// The largest write the kernel is asked to send in a single request
+const MAX_WRITE: u32 = 1024 * 1024;
+
+/// How long the kernel may take to complete the FUSE handshake after PartitionFS is mounted
+const MOUNT_TIMEOUT: Duration = Duration::from_secs(10);
 
 /// This filesystem exposes the partitions of a virtual disk to the host system via FUSE.
 /// Since the partitions on the disk is set beforehand, it's safe to keep the file structure in memory.
+/// Alongside the partitions are `disk`, covering the whole device, and a read-only `table.json` describing the layout.
 pub(crate) struct PartitionFS {
-    paths: Arc<PathManager>,
     mount_time: SystemTime,
 
     sector_size: u64,
+    /// The size of the whole disk in sectors
+    sectors: u64,
 
-    backing: BufReaderWriterRand<File>,
-    root: DirItem,
-
-    buffer: Vec<u8>,
+    /// Accessed with positional reads and writes, so requests don't contend over a shared cursor
+    image: File,
+    /// Every node in the filesystem, indexed by `inode - 1`
+    inodes: Vec<DirItem>,
 
+    /// Maps open file handles to the inode they were opened on
     fd_map: HashMap<u64, u64>,
+    next_fh: u64,
+
+    /// Signalled once the kernel has initialised the filesystem
+    ready: Option<Sender<()>>,
 }
 
 struct Attrs {
@@ -104,18 +117,28 @@ type Sector = u64;
 
 #[derive(Debug)]
 enum FsNode {
-    Dir(Vec<DirItem>),
+    /// The inodes of the directory's children
+    Dir(Vec<u64>),
     Partition {
         name: String,
         start: Sector,
         len: Sector,
     },
+    /// A read-only file whose contents are generated at mount time
+    File(Vec<u8>),
 }
 
 impl DirItem {
     fn getattr(&self) -> FileAttr {
         match &self.node {
             FsNode::Dir(_) => Attrs::dir(),
+            FsNode::File(contents) => Attrs {
+                mtime: self.mtime,
+                size: contents.len() as u64,
+                block_size: self.sector_size,
+                kind: FileType::RegularFile,
+                perm: 0o444,
+            },
             FsNode::Partition { len, .. } => {
                 Attrs {
                     mtime: self.mtime,
@@ -128,147 +151,213 @@ impl DirItem {
         }
             .into_fileattrs(self.inode)
     }
+}
 
-    fn readdir(&self) -> Result<impl Iterator<Item=&DirItem>> {
-        if let FsNode::Dir(children) = &self.node {
-            Ok(children.iter())
-        } else {
-            Err(Error::from(std::io::Error::from_raw_os_error(libc::EINVAL)))
-        }
-    }
-
-    fn contains_inode(&self, inode: u64) -> bool {
-        if self.inode == inode {
-            return true;
+impl PartitionFS {
+    /// Mounts the partitions of the disk at `PathManager::partitions`, returning once the mount is usable.
+    /// The filesystem is unmounted when the returned session is joined or dropped.
+    pub(crate) fn mount(paths: &PathManager) -> Result<BackgroundSession> {
+        let (ready, signal) = channel();
+        let mut pfs = PartitionFS::new(paths)?;
+        pfs.ready = Some(ready);
+
+        let opt = vec![
+            MountOption::DefaultPermissions,
+            MountOption::FSName("PartitionFS".to_owned()),
+            MountOption::RW,
+        ];
+
+        let session = spawn_mount2(pfs, paths.partitions(), &opt)?;
+
+        match signal.recv_timeout(MOUNT_TIMEOUT) {
+            Ok(()) => {
+                debug!("PartitionFS mounted at {:?}", paths.partitions());
+                Ok(session)
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                Err(BuildError::FuseError("Timed out waiting for PartitionFS to initialise".into()).into())
+            }
+            // The session's thread has already exited, taking the sender with it
+            Err(RecvTimeoutError::Disconnected) => {
+                Err(BuildError::FuseError("PartitionFS exited before it was initialised".into()).into())
+            }
         }
-
-        return if let FsNode::Dir(dir) = &self.node {
-            dir.iter().any(|i| i.contains_inode(inode))
-        } else {
-            false
-        };
     }
 
-    fn get_by_inode(&self, inode: u64) -> Option<&DirItem> {
-        return if self.inode == inode {
-            Some(self)
-        } else if let FsNode::Dir(dir) = &self.node {
-            dir.iter()
-                .filter_map(|dir_item| dir_item.get_by_inode(inode))
-                .next()
-        } else {
-            None
-        };
-    }
-}
-
-impl PartitionFS {
-    pub(crate) fn new(paths: Arc<PathManager>) -> Result<Self> {
+    pub(crate) fn new(paths: &PathManager) -> Result<Self> {
         let mount_time = SystemTime::now();
 
-        let (root, sector_size) = {
+        let image = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(paths.final_image())?;
+
+        let (mut children, sector_size) = {
             let mut dev = Device::new(&paths.final_image())?;
             dev.open()?;
             let sector = dev.sector_size();
             let mut disk = Disk::new(&mut dev)?;
 
             (
-                DirItem {
-                    parent: None,
-                    name: "".into(),
-                    inode: 1,
-                    mtime: mount_time,
-                    sector_size: sector,
-                    node: FsNode::Dir(
-                        disk.parts()
-                            .filter_map(|part| part.name().map(|i| (i, part)))
-                            .into_iter()
-                            .enumerate()
-                            .map(|(inode, (name, partition))| DirItem {
-                                name: name.clone(),
-                                parent: Some(0),
-                                sector_size: sector,
-                                inode: inode as u64 + 2,
-                                node: FsNode::Partition {
-                                    name: name.clone(),
-                                    start: partition.geom_start() as u64,
-                                    len: partition.geom_length() as u64,
-                                },
-                                mtime: mount_time,
-                            })
-                            .collect(),
-                    ),
-                },
+                disk.parts()
+                    .filter_map(|part| part.name().map(|i| (i, part)))
+                    .into_iter()
+                    .enumerate()
+                    .map(|(inode, (name, partition))| DirItem {
+                        name: name.clone(),
+                        parent: Some(1),
+                        sector_size: sector,
+                        inode: inode as u64 + 2,
+                        node: FsNode::Partition {
+                            name: name.clone(),
+                            start: partition.geom_start() as u64,
+                            len: partition.geom_length() as u64,
+                        },
+                        mtime: mount_time,
+                    })
+                    .collect::<Vec<_>>(),
                 sector,
             )
         };
 
+        let sectors = image.metadata()?.len() / sector_size;
+
+        let table = json!({
+            "sector_size": sector_size,
+            "sectors": sectors,
+            "partitions": gpt::read(&image)?
+                .iter()
+                .map(|partition| json!({
+                    "name": partition.name,
+                    "start": partition.first_lba,
+                    "len": (partition.last_lba + 1).saturating_sub(partition.first_lba),
+                    "type": gpt::format_guid(&partition.type_guid),
+                    "guid": gpt::format_guid(&partition.guid),
+                    "attributes": partition.attributes,
+                    "flags": partition.flags(),
+                }))
+                .collect::<Vec<_>>(),
+        });
+
+        for (name, node) in [
+            ("disk", FsNode::Partition { name: "disk".into(), start: 0, len: sectors }),
+            ("table.json", FsNode::File(serde_json::to_vec_pretty(&table)?)),
+        ] {
+            if children.iter().any(|partition| partition.name == name) {
+                warn!("Partition {} is hidden by PartitionFS' own {} node", name, name);
+                children.retain(|partition| partition.name != name);
+            }
+
+            children.push(DirItem {
+                parent: Some(1),
+                name: name.into(),
+                inode: 0,
+                node,
+                mtime: mount_time,
+                sector_size,
+            });
+        }
+
+        // Number the nodes by their position in the table, now that the set of children is final
+        for (index, child) in children.iter_mut().enumerate() {
+            child.inode = index as u64 + 2;
+        }
+
+        let mut inodes = vec![DirItem {
+            parent: None,
+            name: "".into(),
+            inode: 1,
+            mtime: mount_time,
+            sector_size,
+            node: FsNode::Dir(children.iter().map(|child| child.inode).collect()),
+        }];
+        inodes.extend(children);
+
         Ok(Self {
             mount_time,
-            root,
+            inodes,
             sector_size,
-            paths: Arc::clone(&paths),
-            buffer: Vec::with_capacity(MIN_BLOCK_SIZE as usize),
+            sectors,
             fd_map: HashMap::new(),
-            backing: BufReaderWriterRand::new_reader(
-                OpenOptions::new()
-                    .read(true)
-                    .write(true)
-                    .open(paths.final_image())?,
-            ),
+            next_fh: 1,
+            ready: None,
+            image,
         })
     }
 
-    fn read_partition(&mut self, inode: u64, offset: u64, size: u64) -> Result<&[u8]> {
-        let e_no_ent = Error::from(std::io::Error::from_raw_os_error(libc::ENOENT));
-        let e_inval: Error = Error::from(std::io::Error::from_raw_os_error(libc::EINVAL));
+    fn node(&self, inode: u64) -> Option<&DirItem> {
+        self.inodes.get((inode as usize).checked_sub(1)?)
+    }
 
-        let FsNode::Partition { start, len, .. } =
-            self.root.get_by_inode(inode).ok_or(e_inval)?.node
-            else {
-                return Err(e_no_ent);
-            };
+    /// The offset and length in bytes of the partition at `inode`
+    fn partition_bounds(&self, inode: u64) -> Result<(u64, u64)> {
+        match self.node(inode).map(|item| &item.node) {
+            Some(FsNode::Partition { start, len, .. }) => Ok((start * self.sector_size, len * self.sector_size)),
+            Some(FsNode::Dir(_)) => Err(std::io::Error::from_raw_os_error(libc::EISDIR).into()),
+            Some(FsNode::File(_)) => Err(std::io::Error::from_raw_os_error(libc::EROFS).into()),
+            None => Err(std::io::Error::from_raw_os_error(libc::ENOENT).into()),
+        }
+    }
 
-        let _ = self
-            .backing
-            .seek(SeekFrom::Start(start * self.sector_size + offset))?;
+    /// Looks up the inode a file handle was opened on
+    fn handle(&self, fh: u64) -> Result<u64> {
+        self.fd_map
+            .get(&fh)
+            .copied()
+            .ok_or(std::io::Error::from_raw_os_error(libc::EBADF).into())
+    }
 
-        // TODO: Ensure offset + size doesn't run over the partition's end
-        let len = size.min(len * self.sector_size - offset);
-        let cap = self.buffer.capacity() as u64;
-        let end = len.min(MAX_BLOCK_SIZE).min(cap) as usize;
+    /// Reads up to `size` bytes. Reads starting at or beyond the end of the partition return nothing.
+    fn read_partition(&self, inode: u64, offset: u64, size: u64) -> Result<Cow<'_, [u8]>> {
+        if let Some(FsNode::File(contents)) = self.node(inode).map(|item| &item.node) {
+            let start = (offset as usize).min(contents.len());
+            let end = start.saturating_add(size as usize).min(contents.len());
 
-        if self.buffer.len() < end {
-            self.buffer.extend(std::iter::repeat(0).take(end - self.buffer.len()));
+            return Ok(Cow::Borrowed(&contents[start..end]));
         }
 
-        let read = self
-            .backing
-            .read(&mut self.buffer[0..end])?;
+        let (start, len) = self.partition_bounds(inode)?;
+
+        if offset >= len {
+            return Ok(Cow::Borrowed(&[]));
+        }
 
-        Ok(&self.buffer[0..read])
+        let mut buffer = vec![0u8; size.min(len - offset) as usize];
+
+        // A short reply is taken as end-of-file, so keep reading until the request is satisfied
+        let mut read = 0;
+        while read < buffer.len() {
+            match self.image.read_at(&mut buffer[read..], start + offset + read as u64) {
+                Ok(0) => break,
+                Ok(len) => read += len,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        buffer.truncate(read);
+        Ok(Cow::Owned(buffer))
     }
 
-    fn write_partition(&mut self, inode: u64, offset: u64, data: &[u8]) -> Result<usize> {
-        let e_no_ent = Error::from(std::io::Error::from_raw_os_error(libc::ENOENT));
-        let e_inval: Error = Error::from(std::io::Error::from_raw_os_error(libc::EINVAL));
+    /// Writes as much of `data` as fits in the partition. Writing beyond the end fails with `ENOSPC`.
+    fn write_partition(&self, inode: u64, offset: u64, data: &[u8]) -> Result<usize> {
+        let (start, len) = self.partition_bounds(inode)?;
 
-        let FsNode::Partition { start, len, .. } =
-            self.root.get_by_inode(inode).ok_or(e_inval)?.node
-            else {
-                return Err(e_no_ent);
-            };
+        let fits = data.len().min(len.saturating_sub(offset) as usize);
+        if fits == 0 && !data.is_empty() {
+            return Err(std::io::Error::from_raw_os_error(libc::ENOSPC).into());
+        }
 
-        let _ = self
-            .backing
-            .seek(SeekFrom::Start(start * self.sector_size + offset))?;
+        self.image.write_all_at(&data[0..fits], start + offset)?;
 
-        // TODO: Ensure offset + size doesn't run over the partition's end
-        let written = self
-            .backing
-            .write(&data[0..data.len().min((len * self.sector_size - offset) as usize)])?;
+        Ok(fits)
+    }
+}
 
-        Ok(written)
+fn errno(err: Error) -> c_int {
+    match err.into_inner() {
+        Inner::IoError(io_err) => io_err.raw_os_error().unwrap_or(libc::EIO),
+        _ => libc::EIO,
     }
 }
 
@@ -278,7 +367,20 @@ impl Filesystem for PartitionFS {
     fn init(
         &mut self, _req: &Request<'_>, config: &mut KernelConfig,
     ) -> std::result::Result<(), c_int> {
-        // config.add_capabilities()?;
+        // Without these, the kernel splits writes into page-sized requests
+        if let Err(unsupported) = config.add_capabilities(consts::FUSE_BIG_WRITES) {
+            debug!("Kernel does not support capabilities {:#x}", unsupported);
+        }
+
+        if let Err(max) = config.set_max_write(MAX_WRITE) {
+            debug!("Kernel limits writes to {} bytes", max);
+            let _ = config.set_max_write(max);
+        }
+
+        if let Some(ready) = self.ready.take() {
+            let _ = ready.send(());
+        }
+
         Ok(())
     }
 
@@ -286,10 +388,11 @@ impl Filesystem for PartitionFS {
         if let Some(DirItem {
                         node: FsNode::Dir(children),
                         ..
-                    }) = self.root.get_by_inode(parent)
+                    }) = self.node(parent)
         {
             if let Some(child) = children
                 .iter()
+                .filter_map(|inode| self.node(*inode))
                 .find(|child| name.eq(&OsString::from(&child.name)))
             {
                 return reply.entry(&TTL, &child.getattr(), 0);
@@ -300,91 +403,150 @@ impl Filesystem for PartitionFS {
     }
 
     fn getattr(&mut self, _req: &Request<'_>, inode: u64, mut reply: ReplyAttr) {
-        if let Some(item) = self.root.get_by_inode(inode) {
+        if let Some(item) = self.node(inode) {
             reply.attr(&TTL, &item.getattr());
         } else {
             reply.error(libc::ENOENT);
         }
     }
 
-    fn open(&mut self, _req: &Request<'_>, inode: u64, _flags: i32, reply: ReplyOpen) {
-        let fd = self.fd_map.keys().cloned().max().unwrap_or(0) + 1;
+    fn setattr(
+        &mut self, _req: &Request<'_>, inode: u64, _mode: Option<u32>, _uid: Option<u32>, _gid: Option<u32>,
+        size: Option<u64>, _atime: Option<TimeOrNow>, _mtime: Option<TimeOrNow>, _ctime: Option<SystemTime>,
+        _fh: Option<u64>, _crtime: Option<SystemTime>, _chgtime: Option<SystemTime>, _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>, reply: ReplyAttr,
+    ) {
+        let Some(item) = self.node(inode) else {
+            return reply.error(libc::ENOENT);
+        };
+
+        let attr = item.getattr();
+
+        // Partitions can't be resized, but truncating to the current size is harmless
+        match size {
+            Some(size) if size != attr.size => reply.error(libc::EPERM),
+            _ => reply.attr(&TTL, &attr),
+        }
+    }
+
+    fn open(&mut self, _req: &Request<'_>, inode: u64, flags: i32, reply: ReplyOpen) {
+        match self.node(inode).map(|item| &item.node) {
+            None => return reply.error(libc::ENOENT),
+            Some(FsNode::Dir(_)) => return reply.error(libc::EISDIR),
+            Some(FsNode::File(_)) if flags & libc::O_ACCMODE != libc::O_RDONLY => return reply.error(libc::EROFS),
+            _ => {}
+        }
+
+        let fh = self.next_fh;
+        self.next_fh += 1;
 
-        debug!("Opened Partition - FD: {}", fd);
+        debug!("Opened Partition - FD: {}", fh);
 
-        self.fd_map.insert(fd, inode);
+        self.fd_map.insert(fh, inode);
 
-        reply.opened(0, 0);
+        reply.opened(fh, 0);
     }
 
     fn read(
-        &mut self, _req: &Request<'_>, inode: u64, _fh: u64, offset: i64, size: u32, flags: i32,
+        &mut self, _req: &Request<'_>, _inode: u64, fh: u64, offset: i64, size: u32, flags: i32,
         lock_owner: Option<u64>, reply: ReplyData,
     ) {
-        let data = match self
-            .read_partition(inode, offset as u64, size as u64)
-            .map_err(Error::into_inner)
-        {
-            Ok(len) => len,
-            Err(Inner::IoError(io_err)) => {
-                return reply.error(io_err.raw_os_error().unwrap_or(libc::EINVAL));
-            }
-            Err(_) => return reply.error(libc::EINVAL),
+        let inode = match self.handle(fh) {
+            Ok(inode) => inode,
+            Err(err) => return reply.error(errno(err)),
         };
 
-        reply.data(data);
+        match self.read_partition(inode, offset as u64, size as u64) {
+            Ok(data) => reply.data(&data),
+            Err(err) => reply.error(errno(err)),
+        }
     }
 
     fn write(
-        &mut self, _req: &Request<'_>, inode: u64, fh: u64, offset: i64, data: &[u8],
+        &mut self, _req: &Request<'_>, _inode: u64, fh: u64, offset: i64, data: &[u8],
         write_flags: u32, flags: i32, lock_owner: Option<u64>, reply: ReplyWrite,
     ) {
         let written = match self
-            .write_partition(inode, offset as u64, data)
-            .map_err(Error::into_inner)
+            .handle(fh)
+            .and_then(|inode| self.write_partition(inode, offset as u64, data))
         {
             Ok(len) => len,
-            Err(Inner::IoError(io_err)) => {
-                return reply.error(io_err.raw_os_error().unwrap_or(libc::EINVAL));
-            }
-            Err(_) => return reply.error(libc::EINVAL),
+            Err(err) => return reply.error(errno(err)),
         };
 
         reply.written(written as u32);
     }
 
     fn flush(&mut self, _req: &Request<'_>, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
+        // Writes go straight to the image, so there is nothing buffered to flush
         reply.ok();
     }
 
+    fn release(
+        &mut self, _req: &Request<'_>, _inode: u64, fh: u64, _flags: i32, _lock_owner: Option<u64>, _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        match self.fd_map.remove(&fh) {
+            Some(_) => {
+                debug!("Closed Partition - FD: {}", fh);
+                reply.ok()
+            }
+            None => reply.error(libc::EBADF),
+        }
+    }
+
+    fn fsync(&mut self, _req: &Request<'_>, _inode: u64, fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        match self.handle(fh).and_then(|_| Ok(self.image.sync_data()?)) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(errno(err)),
+        }
+    }
+
+    fn statfs(&mut self, _req: &Request<'_>, _inode: u64, reply: ReplyStatfs) {
+        // Partitions occupy their full size, so there is never any free space
+        reply.statfs(
+            self.sectors,
+            0,
+            0,
+            self.inodes.len() as u64,
+            0,
+            self.sector_size as u32,
+            255,
+            self.sector_size as u32,
+        );
+    }
+
     fn readdir(
         &mut self, _req: &Request<'_>, inode: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory,
     ) {
-        if let Some(parent) = self.root.get_by_inode(inode) {
-            match parent.readdir().map_err(|err| err.into_inner()) {
-                Ok(dir) => {
-                    for (offset, dir_item) in dir.enumerate().skip(offset as usize) {
-                        if reply.add(
-                            dir_item.inode,
-                            (offset + 3) as i64,
-                            match dir_item.node {
-                                FsNode::Dir(_) => FileType::Directory,
-                                FsNode::Partition { .. } => FileType::BlockDevice,
-                            },
-                            OsString::from(&dir_item.name),
-                        ) {
-                            break;
-                        }
-                    }
-                    reply.ok()
-                }
-                Err(Inner::IoError(io_err)) => {
-                    reply.error(io_err.raw_os_error().unwrap_or(libc::EINVAL))
-                }
-                Err(_) => reply.error(libc::EINVAL),
+        let Some(parent) = self.node(inode) else {
+            return reply.error(libc::ENOENT);
+        };
+
+        let FsNode::Dir(children) = &parent.node else {
+            return reply.error(libc::ENOTDIR);
+        };
+
+        for (index, dir_item) in children
+            .iter()
+            .filter_map(|inode| self.node(*inode))
+            .enumerate()
+            .skip(offset as usize)
+        {
+            if reply.add(
+                dir_item.inode,
+                (index + 1) as i64,
+                match dir_item.node {
+                    FsNode::Dir(_) => FileType::Directory,
+                    FsNode::Partition { .. } => FileType::BlockDevice,
+                    FsNode::File(_) => FileType::RegularFile,
+                },
+                OsString::from(&dir_item.name),
+            ) {
+                break;
             }
-        } else {
-            reply.error(libc::ENOENT);
         }
+
+        reply.ok()
     }
 }
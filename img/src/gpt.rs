@@ -0,0 +1,414 @@
+use std::fs::File;
+use std::io::Read;
+
+use hub::error::*;
+
+use crate::block::BlockDevice;
+
+pub const SECTOR_SIZE: u64 = 512;
+
+const SIGNATURE: &[u8; 8] = b"EFI PART";
+const REVISION: u32 = 0x00010000;
+const HEADER_SIZE: u32 = 92;
+const ENTRY_COUNT: u32 = 128;
+const ENTRY_SIZE: u32 = 128;
+const ENTRY_SECTORS: u64 = (ENTRY_COUNT * ENTRY_SIZE) as u64 / SECTOR_SIZE;
+
+/// Type GUID used for FAT partitions, matching what libparted assigns
+pub const BASIC_DATA: &str = "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7";
+/// Type GUID used for all other partitions
+pub const LINUX_DATA: &str = "0FC63DAF-8483-4772-8E79-3D69D8477DE4";
+
+/// Names of the partition attribute bits defined by the UEFI specification
+const ATTRIBUTES: [(u64, &str); 3] = [
+    (1 << 0, "required"),
+    (1 << 1, "no_block_io"),
+    (1 << 2, "legacy_bios_bootable"),
+];
+
+/// A single entry in the GUID partition table
+#[derive(Debug, Clone)]
+pub struct GptPartition {
+    pub type_guid: [u8; 16],
+    pub guid: [u8; 16],
+    pub first_lba: u64,
+    pub last_lba: u64,
+    pub attributes: u64,
+    pub name: String,
+}
+
+impl GptPartition {
+    /// The names of the attribute bits set on this partition
+    pub fn flags(&self) -> Vec<&'static str> {
+        ATTRIBUTES
+            .iter()
+            .filter(|(bit, _)| self.attributes & bit != 0)
+            .map(|(_, name)| *name)
+            .collect()
+    }
+}
+
+/// The first and last sectors partitions may occupy on a disk of `sectors` sectors.
+/// Fails with `BuildError::DiskTooSmall` if the partition tables leave no room for any.
+pub fn usable_range(sectors: u64) -> Result<(u64, u64)> {
+    let first = 2 + ENTRY_SECTORS;
+
+    match sectors.checked_sub(first) {
+        Some(last) if last >= first => Ok((first, last)),
+        _ => Err(BuildError::DiskTooSmall(sectors).into()),
+    }
+}
+
+/// Parses a UUID in its textual form into bytes in the order they're written, as RFC 4122 and RedoxFS store them
+pub fn parse_uuid(uuid: &str) -> Option<[u8; 16]> {
+    let hex = uuid.replace('-', "");
+    if hex.len() != 32 {
+        return None;
+    }
+
+    let mut bytes = [0u8; 16];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+
+    Some(bytes)
+}
+
+/// Parses a GUID in its textual form into its on-disk (mixed-endian) representation. Unlike `parse_uuid`, the first
+/// three groups are stored little-endian.
+pub fn parse_guid(guid: &str) -> Option<[u8; 16]> {
+    let mut bytes = parse_uuid(guid)?;
+
+    bytes[0..4].reverse();
+    bytes[4..6].reverse();
+    bytes[6..8].reverse();
+
+    Some(bytes)
+}
+
+/// Formats a GUID from its on-disk representation
+pub fn format_guid(guid: &[u8; 16]) -> String {
+    let mut bytes = *guid;
+    bytes[0..4].reverse();
+    bytes[4..6].reverse();
+    bytes[6..8].reverse();
+
+    let hex = bytes.iter().map(|b| format!("{:02X}", b)).collect::<String>();
+    format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32])
+}
+
+/// Generates a random (version 4) GUID
+pub fn random_guid() -> Result<[u8; 16]> {
+    let mut guid = [0u8; 16];
+    File::open("/dev/urandom")?.read_exact(&mut guid)?;
+
+    guid[7] = (guid[7] & 0x0F) | 0x40;
+    guid[8] = (guid[8] & 0x3F) | 0x80;
+
+    Ok(guid)
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, byte| {
+        (0..8).fold(crc ^ *byte as u32, |crc, _| match crc & 1 {
+            1 => (crc >> 1) ^ 0xEDB88320,
+            _ => crc >> 1,
+        })
+    })
+}
+
+fn header(
+    current: u64, backup: u64, entries: u64, (first, last): (u64, u64), disk_guid: &[u8; 16], entries_crc: u32,
+) -> Vec<u8> {
+    let mut header = Vec::with_capacity(SECTOR_SIZE as usize);
+
+    header.extend_from_slice(SIGNATURE);
+    header.extend_from_slice(&REVISION.to_le_bytes());
+    header.extend_from_slice(&HEADER_SIZE.to_le_bytes());
+    header.extend_from_slice(&0u32.to_le_bytes()); // CRC, filled in below
+    header.extend_from_slice(&0u32.to_le_bytes());
+    header.extend_from_slice(&current.to_le_bytes());
+    header.extend_from_slice(&backup.to_le_bytes());
+    header.extend_from_slice(&first.to_le_bytes());
+    header.extend_from_slice(&last.to_le_bytes());
+    header.extend_from_slice(disk_guid);
+    header.extend_from_slice(&entries.to_le_bytes());
+    header.extend_from_slice(&ENTRY_COUNT.to_le_bytes());
+    header.extend_from_slice(&ENTRY_SIZE.to_le_bytes());
+    header.extend_from_slice(&entries_crc.to_le_bytes());
+
+    let crc = crc32(&header);
+    header[16..20].copy_from_slice(&crc.to_le_bytes());
+    header.resize(SECTOR_SIZE as usize, 0);
+
+    header
+}
+
+fn protective_mbr(sectors: u64) -> Vec<u8> {
+    let mut mbr = vec![0u8; SECTOR_SIZE as usize];
+
+    mbr[446..462].copy_from_slice(&[
+        0x00, 0x00, 0x02, 0x00, // Non-bootable, CHS start
+        0xEE, 0xFF, 0xFF, 0xFF, // GPT protective, CHS end
+        0x01, 0x00, 0x00, 0x00, // Starting LBA
+        0, 0, 0, 0, // Size, filled in below
+    ]);
+    mbr[458..462].copy_from_slice(&((sectors - 1).min(u32::MAX as u64) as u32).to_le_bytes());
+    mbr[510] = 0x55;
+    mbr[511] = 0xAA;
+
+    mbr
+}
+
+/// Writes a protective MBR along with primary and backup GUID partition tables to the device
+pub fn write(device: &dyn BlockDevice, partitions: &[GptPartition]) -> Result<()> {
+    if partitions.len() > ENTRY_COUNT as usize {
+        return Err(BuildError::TooManyPartitions(partitions.len()).into());
+    }
+
+    let sectors = device.size()? / SECTOR_SIZE;
+    let usable = usable_range(sectors)?;
+    let disk_guid = random_guid()?;
+
+    let mut entries = vec![0u8; (ENTRY_COUNT * ENTRY_SIZE) as usize];
+    for (entry, partition) in entries.chunks_mut(ENTRY_SIZE as usize).zip(partitions) {
+        entry[0..16].copy_from_slice(&partition.type_guid);
+        entry[16..32].copy_from_slice(&partition.guid);
+        entry[32..40].copy_from_slice(&partition.first_lba.to_le_bytes());
+        entry[40..48].copy_from_slice(&partition.last_lba.to_le_bytes());
+        entry[48..56].copy_from_slice(&partition.attributes.to_le_bytes());
+
+        for (i, unit) in partition.name.encode_utf16().take(36).enumerate() {
+            entry[56 + i * 2..58 + i * 2].copy_from_slice(&unit.to_le_bytes());
+        }
+    }
+
+    let entries_crc = crc32(&entries);
+    let last = sectors - 1;
+    let backup_entries = last - ENTRY_SECTORS;
+
+    device.write_all_at(0, &protective_mbr(sectors))?;
+    device.write_all_at(SECTOR_SIZE, &header(1, last, 2, usable, &disk_guid, entries_crc))?;
+    device.write_all_at(2 * SECTOR_SIZE, &entries)?;
+    device.write_all_at(backup_entries * SECTOR_SIZE, &entries)?;
+    device.write_all_at(last * SECTOR_SIZE, &header(last, 1, backup_entries, usable, &disk_guid, entries_crc))?;
+    device.flush()?;
+
+    Ok(())
+}
+
+/// Reads the partitions from the primary GUID partition table on the device. Unused entries are skipped.
+pub fn read(device: &dyn BlockDevice) -> Result<Vec<GptPartition>> {
+    usable_range(device.size()? / SECTOR_SIZE)?;
+
+    let mut header = vec![0u8; SECTOR_SIZE as usize];
+    device.read_exact_at(SECTOR_SIZE, &mut header)?;
+
+    if &header[0..8] != SIGNATURE {
+        return Err(BuildError::InvalidDiskType.into());
+    }
+
+    let u32_at = |offset: usize| u32::from_le_bytes(header[offset..offset + 4].try_into().expect("Slice is 4 bytes"));
+    let u64_at = |offset: usize| u64::from_le_bytes(header[offset..offset + 8].try_into().expect("Slice is 8 bytes"));
+
+    let entries_lba = u64_at(72);
+    let count = u32_at(80) as usize;
+    let size = u32_at(84) as usize;
+
+    if size < 128 {
+        return Err(BuildError::InvalidDiskType.into());
+    }
+
+    let mut entries = vec![0u8; count * size];
+    device.read_exact_at(entries_lba * SECTOR_SIZE, &mut entries)?;
+
+    Ok(entries
+        .chunks(size)
+        .filter(|entry| entry[0..16].iter().any(|b| *b != 0))
+        .map(|entry| {
+            let name = entry[56..128]
+                .chunks(2)
+                .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+                .take_while(|unit| *unit != 0)
+                .collect::<Vec<u16>>();
+
+            GptPartition {
+                type_guid: entry[0..16].try_into().expect("Slice is 16 bytes"),
+                guid: entry[16..32].try_into().expect("Slice is 16 bytes"),
+                first_lba: u64::from_le_bytes(entry[32..40].try_into().expect("Slice is 8 bytes")),
+                last_lba: u64::from_le_bytes(entry[40..48].try_into().expect("Slice is 8 bytes")),
+                attributes: u64::from_le_bytes(entry[48..56].try_into().expect("Slice is 8 bytes")),
+                name: String::from_utf16_lossy(&name),
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+
+    use hub::global::Inner;
+
+    use super::*;
+
+    /// A sparse file of the given size for a test to write a partition table to
+    fn disk(name: &str, sectors: u64) -> File {
+        let path = std::env::temp_dir().join(format!("redox-builder-{}-{}.img", name, std::process::id()));
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        file.set_len(sectors * SECTOR_SIZE).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        file
+    }
+
+    fn sector(device: &dyn BlockDevice, lba: u64) -> Vec<u8> {
+        let mut sector = vec![0; SECTOR_SIZE as usize];
+        device.read_exact_at(lba * SECTOR_SIZE, &mut sector).unwrap();
+        sector
+    }
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn crc32_matches_known_vector() {
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn guids_are_stored_mixed_endian() {
+        let guid = parse_guid(BASIC_DATA).unwrap();
+
+        assert_eq!(guid, [
+            0xA2, 0xA0, 0xD0, 0xEB, 0xE5, 0xB9, 0x33, 0x44, 0x87, 0xC0, 0x68, 0xB6, 0xB7, 0x26, 0x99, 0xC7
+        ]);
+        assert_eq!(format_guid(&guid), BASIC_DATA);
+        assert_eq!(format_guid(&parse_guid(&LINUX_DATA.to_lowercase()).unwrap()), LINUX_DATA);
+    }
+
+    #[test]
+    fn uuids_are_stored_in_order() {
+        let uuid = parse_uuid("123e4567-e89b-12d3-a456-426614174000").unwrap();
+
+        assert_eq!(uuid, [
+            0x12, 0x3e, 0x45, 0x67, 0xe8, 0x9b, 0x12, 0xd3, 0xa4, 0x56, 0x42, 0x66, 0x14, 0x17, 0x40, 0x00
+        ]);
+        assert_eq!(format_guid(&parse_guid("123e4567-e89b-12d3-a456-426614174000").unwrap()), "123E4567-E89B-12D3-A456-426614174000");
+        assert_ne!(parse_guid("123e4567-e89b-12d3-a456-426614174000").unwrap(), uuid);
+    }
+
+    #[test]
+    fn malformed_guids_are_rejected() {
+        assert_eq!(parse_guid("EBD0A0A2-B9E5-4433-87C0"), None);
+        assert_eq!(parse_guid("XBD0A0A2-B9E5-4433-87C0-68B6B72699C7"), None);
+        assert_eq!(parse_guid("ÉBD0A0A2-B9E5-4433-87C0-68B6B72699C"), None);
+        assert_eq!(parse_uuid("123e4567-e89b-12d3-a456-42661417400g"), None);
+    }
+
+    #[test]
+    fn usable_range_leaves_room_for_both_tables() {
+        assert_eq!(usable_range(2048).unwrap(), (34, 2014));
+        assert_eq!(usable_range(68).unwrap(), (34, 34));
+
+        for sectors in [0, 33, 34, 67] {
+            let err = usable_range(sectors).unwrap_err();
+            assert!(matches!(err.inner(), Inner::BuildError(BuildError::DiskTooSmall(_))));
+        }
+    }
+
+    #[test]
+    fn written_tables_read_back() {
+        let device = disk("gpt", 2048);
+        let partitions = vec![
+            GptPartition {
+                type_guid: parse_guid(BASIC_DATA).unwrap(),
+                guid: random_guid().unwrap(),
+                first_lba: 34,
+                last_lba: 1023,
+                attributes: 1 << 2,
+                name: "EFI".into(),
+            },
+            GptPartition {
+                type_guid: parse_guid(LINUX_DATA).unwrap(),
+                guid: random_guid().unwrap(),
+                first_lba: 1024,
+                last_lba: 2014,
+                attributes: 0,
+                name: "RedoxFS".into(),
+            },
+        ];
+
+        write(&device, &partitions).unwrap();
+
+        let read = read(&device).unwrap();
+        assert_eq!(read.len(), 2);
+        for (read, written) in read.iter().zip(partitions.iter()) {
+            assert_eq!(read.type_guid, written.type_guid);
+            assert_eq!(read.guid, written.guid);
+            assert_eq!((read.first_lba, read.last_lba), (written.first_lba, written.last_lba));
+            assert_eq!(read.attributes, written.attributes);
+            assert_eq!(read.name, written.name);
+        }
+        assert_eq!(read[0].flags(), vec!["legacy_bios_bootable"]);
+
+        let mbr = sector(&device, 0);
+        assert_eq!(mbr[450], 0xEE);
+        assert_eq!(&mbr[510..512], &[0x55, 0xAA]);
+
+        let mut entries = vec![0; (ENTRY_COUNT * ENTRY_SIZE) as usize];
+        device.read_exact_at(2 * SECTOR_SIZE, &mut entries).unwrap();
+
+        // The backup header sits in the last sector and points back at the primary one
+        for (lba, backup, entries_lba) in [(1, 2047, 2), (2047, 1, 2047 - ENTRY_SECTORS)] {
+            let header = sector(&device, lba);
+            assert_eq!(&header[0..8], SIGNATURE);
+            assert_eq!(u64_at(&header, 24), lba);
+            assert_eq!(u64_at(&header, 32), backup);
+            assert_eq!((u64_at(&header, 40), u64_at(&header, 48)), (34, 2014));
+            assert_eq!(u64_at(&header, 72), entries_lba);
+
+            let mut unsigned = header[..HEADER_SIZE as usize].to_vec();
+            unsigned[16..20].fill(0);
+            assert_eq!(u32_at(&header, 16), crc32(&unsigned));
+            assert_eq!(u32_at(&header, 88), crc32(&entries));
+
+            let mut copy = vec![0; entries.len()];
+            device.read_exact_at(entries_lba * SECTOR_SIZE, &mut copy).unwrap();
+            assert_eq!(copy, entries);
+        }
+    }
+
+    #[test]
+    fn tiny_disks_are_rejected() {
+        let err = write(&disk("gpt-tiny", 40), &[]).unwrap_err();
+        assert!(matches!(err.inner(), Inner::BuildError(BuildError::DiskTooSmall(40))));
+    }
+
+    #[test]
+    fn tiny_disks_are_not_read() {
+        let err = read(&disk("gpt-tiny-read", 1)).unwrap_err();
+        assert!(matches!(err.inner(), Inner::BuildError(BuildError::DiskTooSmall(1))));
+    }
+
+    #[test]
+    fn partitions_must_fit_in_the_table() {
+        let partition = GptPartition {
+            type_guid: [1; 16],
+            guid: [2; 16],
+            first_lba: 34,
+            last_lba: 34,
+            attributes: 0,
+            name: String::new(),
+        };
+
+        let err = write(&disk("gpt-overfull", 2048), &vec![partition; ENTRY_COUNT as usize + 1]).unwrap_err();
+        assert!(matches!(err.inner(), Inner::BuildError(BuildError::TooManyPartitions(129))));
+    }
+}
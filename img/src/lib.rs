@@ -1,14 +1,11 @@
 use std::ffi::CString;
 use std::fs;
-use std::fs::File;
+use std::fs::{File, OpenOptions};
 use std::os::unix::prelude::OsStrExt;
 use std::path::{Path, PathBuf};
-use std::process::Command;
 use std::sync::Arc;
 // use std::sync::mpsc::Receiver;
-use std::time::Duration;
 
-use fuser::{BackgroundSession, mount2, MountOption, spawn_mount2};
 use libparted::Constraint;
 use libparted::Disk;
 use libparted::FileSystemType;
@@ -18,16 +15,19 @@ use libparted::PartitionTableType;
 use libparted::PartitionType;
 use log::{debug, error, info, warn};
 use rayon::prelude::*;
-use redoxfs::DiskFile;
 
-use hub::config::ImageConfig;
+use hub::cancel;
+use hub::config::{Backend, ImageConfig, ImageFormat};
 use hub::error::*;
 use hub::paths::PathManager;
 
-use crate::fuse::PartitionFS;
+use crate::block::BlockDevice;
+use crate::filesystem::{Registry, Target};
+use crate::loopdev::LoopDevice;
+#[cfg(feature = "qemu")]
+use crate::nbd::Nbd;
 #[cfg(feature = "qemu")]
 use crate::qemu::QCow2;
-#[cfg(not(feature = "qemu"))]
 use crate::raw::Raw;
 
 pub mod fuse;
@@ -35,6 +35,17 @@ pub mod fuse;
 pub mod qemu;
 pub mod raw;
 pub mod mnt;
+pub mod loopdev;
+pub mod block;
+pub mod gpt;
+pub mod recover;
+pub mod filesystem;
+pub mod place;
+mod template;
+mod tree;
+pub mod users;
+#[cfg(feature = "qemu")]
+pub mod nbd;
 
 // pub type AbortSignal = Receiver<()>;
 
@@ -54,20 +65,9 @@ pub trait DiskManager {
 
         let img = self.image();
 
-        let total = img.size * 1024i64.pow(2) / dev.sector_size() as i64;
-        let partitions = img
-            .partitions
-            .iter()
-            .scan(total, |remaining, partition| {
-                let start = total - *remaining;
-                let len = match partition.size * 1024i64.pow(2) / dev.sector_size() as i64 {
-                    x if x < 0 => *remaining - x.abs(),
-                    x => x,
-                };
-
-                *remaining = *remaining - len;
-                Some(Geometry::new(&dev, start, len).map_err(Error::from))
-            })
+        let partitions = layout(img, dev.sector_size() as i64)?
+            .into_iter()
+            .map(|(start, len)| Geometry::new(&dev, start, len).map_err(Error::from))
             .collect::<Result<Vec<Geometry>>>()?;
 
         let mut disk = Disk::new_with_partition_table(&mut dev, PartitionTableType::GPT)?;
@@ -98,93 +98,246 @@ pub trait DiskManager {
         disk.commit()?;
         Ok(())
     }
+
+    /// Makes the partitions of the disk available at `PathManager::partition`.
+    /// Backends relying on FUSE mount PartitionFS here and hold on to its session until they're unmounted.
+    fn expose_partitions(&mut self) -> Result<()>;
+
+    /// Opens a partition for direct access.
+    /// By default, this opens the file or block device at `PathManager::partition`.
+    fn open_partition(&self, label: &str) -> Result<Box<dyn BlockDevice>> {
+        let path = self.paths().partition(label).ok_or(BuildError::InvalidPartitionName)?;
+        Ok(Box::new(OpenOptions::new().read(true).write(true).open(path)?))
+    }
+
     fn unmount(&mut self) -> Result<()>;
 }
 
-fn get_disk_manager(img: Arc<ImageConfig>, path: Arc<PathManager>) -> Result<Box<dyn DiskManager>> {
-    #[cfg(feature = "qemu")]
-    return Ok(Box::new(QCow2::create_disk(img, path)?));
+/// Computes the start and length (in sectors) of each partition in the image.
+/// Negative sizes subtract from the space remaining after the preceding partitions.
+pub(crate) fn layout(img: &ImageConfig, sector_size: i64) -> Result<Vec<(i64, i64)>> {
+    let total = img.size * 1024i64.pow(2) / sector_size;
+    let mut remaining = total;
+
+    img.partitions
+        .iter()
+        .map(|partition| {
+            let start = total - remaining;
+            let len = match partition.size * 1024i64.pow(2) / sector_size {
+                x if x < 0 => remaining + x,
+                x => x,
+            };
+
+            if len <= 0 || len > remaining {
+                return Err(BuildError::PartitionTooLarge(partition.label.clone()).into());
+            }
+
+            remaining -= len;
+            Ok((start, len))
+        })
+        .collect()
+}
+
+/// Searches `$PATH` for a host tool
+pub(crate) fn find_tool(tool: &str) -> Option<PathBuf> {
+    std::env::var_os("PATH").and_then(|path| {
+        std::env::split_paths(&path)
+            .map(|dir| dir.join(tool))
+            .find(|bin| bin.is_file())
+    })
+}
+
+/// Determines whether a backend can be used for the image on this host. If not, the reason is returned.
+fn check_backend(backend: Backend, img: &ImageConfig) -> Option<String> {
+    let fuse = || {
+        if !Path::new("/dev/fuse").exists() {
+            Some("/dev/fuse".to_owned())
+        } else if unsafe { libc::geteuid() } != 0 && find_tool("fusermount3").or(find_tool("fusermount")).is_none() {
+            Some("fusermount".to_owned())
+        } else {
+            None
+        }
+    };
 
-    #[cfg(not(feature = "qemu"))]
-    return Ok(Box::new(Raw::create_disk(path, img)?));
+    match backend {
+        #[cfg(feature = "qemu")]
+        Backend::QemuFuse => ["qemu-img", "qemu-storage-daemon"]
+            .into_iter()
+            .find(|tool| find_tool(tool).is_none())
+            .map(str::to_owned)
+            .or_else(fuse),
+        #[cfg(feature = "qemu")]
+        Backend::Nbd => ["qemu-img", "qemu-storage-daemon"]
+            .into_iter()
+            .find(|tool| find_tool(tool).is_none())
+            .map(str::to_owned),
+        #[cfg(not(feature = "qemu"))]
+        Backend::QemuFuse | Backend::Nbd => Some("the qemu feature".to_owned()),
+        Backend::RawFuse => match img.format {
+            ImageFormat::Raw => fuse(),
+            #[allow(unreachable_patterns)]
+            _ => Some("raw image format".to_owned()),
+        },
+        Backend::Loop => match img.format {
+            ImageFormat::Raw if LoopDevice::available() => None,
+            ImageFormat::Raw => Some("superuser access to /dev/loop-control".to_owned()),
+            #[allow(unreachable_patterns)]
+            _ => Some("raw image format".to_owned()),
+        },
+    }
+}
+
+/// Picks the disk backend. If one is explicitly requested, it is used or an error is returned if it isn't usable.
+/// Otherwise the fastest usable backend is chosen. Passing `fuse` excludes backends which don't rely on FUSE.
+fn get_disk_manager(
+    img: Arc<ImageConfig>, path: Arc<PathManager>, backend: Option<Backend>, fuse: bool,
+) -> Result<Box<dyn DiskManager>> {
+    let backend = match backend {
+        Some(backend) => match check_backend(backend, &img) {
+            None => backend,
+            Some(missing) => return Err(BuildError::BackendUnavailable(backend, missing).into()),
+        },
+        None => {
+            let candidates = [
+                (!fuse).then_some(Backend::Loop),
+                Some(Backend::RawFuse),
+                #[cfg(feature = "qemu")]
+                Some(Backend::QemuFuse),
+                #[cfg(feature = "qemu")]
+                (!fuse).then_some(Backend::Nbd),
+            ];
+
+            candidates
+                .into_iter()
+                .flatten()
+                .find(|backend| match check_backend(*backend, &img) {
+                    None => true,
+                    Some(missing) => {
+                        debug!("Backend {:?} unavailable: requires {}", backend, missing);
+                        false
+                    }
+                })
+                .ok_or(BuildError::NoBackendAvailable(img.format))?
+        }
+    };
+
+    info!("Using {:?} disk backend", backend);
+
+    Ok(match backend {
+        #[cfg(feature = "qemu")]
+        Backend::QemuFuse => Box::new(QCow2::create_disk(img, path)?),
+        Backend::RawFuse => Box::new(Raw::create_disk(img, path)?),
+        Backend::Loop => Box::new(LoopDevice::create_disk(img, path)?),
+        #[cfg(feature = "qemu")]
+        Backend::Nbd => Box::new(Nbd::create_disk(img, path)?),
+        #[cfg(not(feature = "qemu"))]
+        Backend::QemuFuse | Backend::Nbd => {
+            return Err(BuildError::BackendUnavailable(backend, "the qemu feature".to_owned()).into())
+        }
+    })
 }
 
 /// This function is responsible for mounting the virtual disk and all its partitions such that each can be written to as if it
-/// TODO: This function is currently implemented using FUSE. If the user has superuser access or simply prefers not to, we should use system-level APIs like loopbacks because they're faster.
-pub fn preload_filesystems(config: Arc<ImageConfig>, path: Arc<PathManager>) -> Result<Box<dyn DiskManager>> {
+/// were a regular block device. If the user has superuser access, loop devices are used because they're faster. Otherwise, FUSE is used.
+pub fn preload_filesystems(
+    config: Arc<ImageConfig>, drivers: &Registry, path: Arc<PathManager>, backend: Option<Backend>, fuse: bool,
+) -> Result<Box<dyn DiskManager>> {
     if !path.partitions().exists() {
         fs::create_dir_all(path.partitions())?;
     }
 
-    let mut disk = get_disk_manager(Arc::clone(&config), Arc::clone(&path))?;
+    let mut disk = get_disk_manager(Arc::clone(&config), Arc::clone(&path), backend, fuse)?;
     disk.mount()?;
-    if let Err(err) = disk.build() {
-        error!("Failed to build disk. Killing storage daemon");
-        disk.unmount()?;
-        return Err(err.into());
-    };
 
-    let paths = Arc::clone(&path);
+    if let Err(err) = prepare_disk(disk.as_mut(), &config, drivers, &path) {
+        error!("Failed to prepare disk. Unmounting");
+        if let Err(err) = disk.unmount() {
+            error!("Failed to unmount disk: {:?}", err);
+        }
 
-    let pfs = PartitionFS::new(Arc::clone(&paths))?;
-    let opt = vec![
-        MountOption::DefaultPermissions,
-        MountOption::FSName("PartitionFS".to_owned()),
-        MountOption::RW,
-    ];
+        return Err(err);
+    }
+
+    Ok(disk)
+}
 
-    let mnt = std::thread::spawn(move || mount2(pfs, paths.partitions(), &opt).expect("Failed to mount PartFS"));
+/// Partitions the mounted disk, exposes its partitions and creates their filesystems
+fn prepare_disk(disk: &mut dyn DiskManager, config: &ImageConfig, drivers: &Registry, path: &PathManager) -> Result<()> {
+    disk.build()?;
+    cancel::checkpoint()?;
 
-    std::thread::sleep(Duration::from_millis(1000));
+    disk.expose_partitions()?;
+    cancel::checkpoint()?;
 
-    let _ = config
+    // Partitions are opened up-front as the disk manager can't be shared across threads
+    let targets = config
         .partitions
-        .par_iter()
-        .map(|partition| -> Result<()> {
-            let partition_blockdev = path.partition(&partition.label).expect(
-                format!("No blockdev defined for partition '{}'", &partition.label).as_ref(),
-            );
-
-            match partition.filesystem.as_ref().map(|i| i.as_str()) {
-                Some("redoxfs") => {
-                    let disk = DiskFile::open(&partition_blockdev)?;
-                    let ctime = std::time::SystemTime::now()
-                        .duration_since(std::time::UNIX_EPOCH)
-                        .unwrap();
-
-                    redoxfs::FileSystem::create_reserved(
-                        disk,
-                        None,
-                        &[],
-                        ctime.as_secs(),
-                        ctime.subsec_nanos(),
-                    )?;
-
-                    Ok(())
-                }
-                Some("fat32") => {
-                    if Command::new("mkfs.fat")
-                        .arg(partition_blockdev)
-                        .spawn()?
-                        .wait()?
-                        .success()
-                    {
-                        Ok(())
-                    } else {
-                        return Err(BuildError::FailedToCreateFilesystem("FAT32".into()).into());
-                    }
-                }
-                // TODO: look up user-defined filesystems from parent config
-                Some(fs) => Err(Error::from(BuildError::UnrecognisedFilesystem(
-                    fs.to_owned(),
-                ))),
-                _ => Ok(()),
+        .iter()
+        .filter_map(|partition| partition.filesystem.as_ref().map(|fs| (partition, fs)))
+        .map(|(partition, fs)| -> Result<_> {
+            let driver = drivers.get(fs)?;
+            if partition.encryption.is_some() && !driver.encryption() {
+                return Err(BuildError::UnsupportedOperation(fs.to_owned(), "encryption".into()).into());
             }
+
+            let target = Target {
+                partition,
+                paths: path,
+                source: path.partition(&partition.label),
+                device: disk.open_partition(&partition.label)?,
+            };
+
+            Ok((driver, target))
         })
         .collect::<Result<Vec<_>>>()?;
 
-    // Hang
-    // mnt.join().expect("Failed to join");
+    targets
+        .into_par_iter()
+        .map(|(driver, target)| driver.create(target))
+        .collect::<Result<Vec<_>>>()?;
 
-    Ok(disk)
+    cancel::checkpoint()
+}
+
+#[cfg(test)]
+mod tests {
+    use hub::global::Inner;
+
+    use super::*;
+
+    fn image(size: i64, partitions: &[i64]) -> ImageConfig {
+        let partitions = partitions
+            .iter()
+            .enumerate()
+            .map(|(i, size)| format!(r#"{{ "label": "part{}", "size": {} }}"#, i, size))
+            .collect::<Vec<_>>();
+
+        serde_json::from_str(&format!(r#"{{ "label": "test", "size": {}, "partition": [{}] }}"#, size, partitions.join(","))).unwrap()
+    }
+
+    fn too_large(result: Result<Vec<(i64, i64)>>) -> String {
+        match result.unwrap_err().into_inner() {
+            Inner::BuildError(BuildError::PartitionTooLarge(label)) => label,
+            err => panic!("Unexpected error: {:?}", err),
+        }
+    }
+
+    #[test]
+    fn partitions_are_laid_out_in_order() {
+        assert_eq!(layout(&image(4, &[1, 2]), 512).unwrap(), [(0, 2048), (2048, 4096)]);
+    }
+
+    #[test]
+    fn negative_sizes_leave_space_after_the_partition() {
+        assert_eq!(layout(&image(8, &[1, -2, 1]), 512).unwrap(), [(0, 2048), (2048, 10240), (12288, 2048)]);
+        assert_eq!(layout(&image(8, &[-1]), 4096).unwrap(), [(0, 1792)]);
+    }
+
+    #[test]
+    fn partitions_which_dont_fit_are_rejected() {
+        assert_eq!(too_large(layout(&image(4, &[2, 3]), 512)), "part1");
+        assert_eq!(too_large(layout(&image(4, &[2, -2]), 512)), "part1");
+        assert_eq!(too_large(layout(&image(4, &[-5]), 512)), "part0");
+        assert_eq!(too_large(layout(&image(4, &[0]), 512)), "part0");
+    }
 }
@@ -0,0 +1,263 @@
+use std::fs::{File, OpenOptions};
+use std::ops::Deref;
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use libc::c_ulong;
+use log::{debug, error, warn};
+
+use hub::config::{ImageConfig, ImageFormat};
+use hub::error::*;
+use hub::paths::PathManager;
+
+use crate::DiskManager;
+
+const LOOP_SET_FD: c_ulong = 0x4C00;
+const LOOP_CLR_FD: c_ulong = 0x4C01;
+const LOOP_SET_STATUS64: c_ulong = 0x4C04;
+const LOOP_CTL_GET_FREE: c_ulong = 0x4C82;
+const BLKRRPART: c_ulong = 0x125F;
+
+const LO_FLAGS_AUTOCLEAR: u32 = 4;
+const LO_FLAGS_PARTSCAN: u32 = 8;
+
+/// How long to wait for udev (or devtmpfs) to create the partition nodes after a rescan
+const PARTITION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Mirrors `struct loop_info64` from `linux/loop.h`
+#[repr(C)]
+struct LoopInfo64 {
+    lo_device: u64,
+    lo_inode: u64,
+    lo_rdevice: u64,
+    lo_offset: u64,
+    lo_sizelimit: u64,
+    lo_number: u32,
+    lo_encrypt_type: u32,
+    lo_encrypt_key_size: u32,
+    lo_flags: u32,
+    lo_file_name: [u8; 64],
+    lo_crypt_name: [u8; 64],
+    lo_encrypt_key: [u8; 32],
+    lo_init: [u64; 2],
+}
+
+/// Exposes a raw image through a kernel loop device.
+/// Partitions are scanned by the kernel and appear as `/dev/loopNpM`, so no FUSE layer is necessary.
+/// This requires superuser access.
+pub struct LoopDevice {
+    backing: PathBuf,
+    image: Arc<ImageConfig>,
+    paths: Arc<PathManager>,
+    device: Option<AttachedLoop>,
+}
+
+struct AttachedLoop {
+    path: PathBuf,
+    file: File,
+}
+
+impl LoopDevice {
+    /// Whether loop devices can be used at all on this host
+    pub fn available() -> bool {
+        unsafe { libc::geteuid() == 0 } && Path::new("/dev/loop-control").exists()
+    }
+
+    /// Detaches any loop devices still backed by `image`, such as those left behind by a crashed build.
+    /// Returns how many were detached.
+    pub(crate) fn detach_stale(image: &Path) -> Result<usize> {
+        let image = std::fs::canonicalize(image)?;
+        let mut detached = 0;
+
+        for entry in std::fs::read_dir("/sys/block")?.filter_map(std::result::Result::ok) {
+            let name = entry.file_name();
+            if !name.to_string_lossy().starts_with("loop") {
+                continue;
+            }
+
+            let Ok(backing) = std::fs::read_to_string(entry.path().join("loop/backing_file")) else {
+                continue;
+            };
+
+            if Path::new(backing.trim_end()) != image {
+                continue;
+            }
+
+            let path = Path::new("/dev").join(&name);
+            warn!("Detaching stale loop device {:?}", &path);
+
+            AttachedLoop {
+                file: OpenOptions::new().read(true).write(true).open(&path)?,
+                path,
+            }
+            .detach()?;
+            detached += 1;
+        }
+
+        Ok(detached)
+    }
+
+    fn attach(image: &Path) -> Result<AttachedLoop> {
+        let control = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/loop-control")?;
+        let backing = OpenOptions::new().read(true).write(true).open(image)?;
+
+        // Another process may claim the free device between us asking for it and binding to it, so retry a few times.
+        let mut attempts = 0;
+        let (path, file) = loop {
+            let index = unsafe { libc::ioctl(control.as_raw_fd(), LOOP_CTL_GET_FREE as _) };
+            if index < 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+
+            let path = PathBuf::from(format!("/dev/loop{}", index));
+            let file = OpenOptions::new().read(true).write(true).open(&path)?;
+
+            if unsafe { libc::ioctl(file.as_raw_fd(), LOOP_SET_FD as _, backing.as_raw_fd()) } == 0 {
+                break (path, file);
+            }
+
+            let err = std::io::Error::last_os_error();
+            attempts += 1;
+            if err.raw_os_error() != Some(libc::EBUSY) || attempts >= 5 {
+                return Err(err.into());
+            }
+        };
+
+        let mut info: LoopInfo64 = unsafe { std::mem::zeroed() };
+        info.lo_flags = LO_FLAGS_PARTSCAN | LO_FLAGS_AUTOCLEAR;
+        let name = image.as_os_str().as_bytes();
+        let len = name.len().min(info.lo_file_name.len() - 1);
+        info.lo_file_name[..len].copy_from_slice(&name[..len]);
+
+        let attached = AttachedLoop { path, file };
+
+        if unsafe { libc::ioctl(attached.file.as_raw_fd(), LOOP_SET_STATUS64 as _, &info) } != 0 {
+            let err = std::io::Error::last_os_error();
+            attached.detach()?;
+            return Err(err.into());
+        }
+
+        debug!("Attached {:?} to {:?}", image, &attached.path);
+
+        Ok(attached)
+    }
+}
+
+impl AttachedLoop {
+    fn partition(&self, index: usize) -> PathBuf {
+        PathBuf::from(format!("{}p{}", self.path.to_string_lossy(), index + 1))
+    }
+
+    /// Asks the kernel to re-read the partition table and waits for the partition nodes to appear
+    fn rescan(&self, partitions: usize) -> Result<()> {
+        if unsafe { libc::ioctl(self.file.as_raw_fd(), BLKRRPART as _) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        let start = Instant::now();
+        while !(0..partitions).all(|i| self.partition(i).exists()) {
+            if start.elapsed() > PARTITION_TIMEOUT {
+                return Err(BuildError::LoopError.into());
+            }
+
+            std::thread::sleep(Duration::from_millis(50));
+        }
+
+        Ok(())
+    }
+
+    fn detach(self) -> Result<()> {
+        debug!("Detaching {:?}", &self.path);
+
+        if unsafe { libc::ioctl(self.file.as_raw_fd(), LOOP_CLR_FD as _) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        Ok(())
+    }
+}
+
+impl DiskManager for LoopDevice {
+    fn backing(&self) -> PathBuf {
+        // The partition table is written through the loop device so the kernel is aware of it
+        match self.device.as_ref() {
+            Some(dev) => dev.path.clone(),
+            None => self.backing.clone(),
+        }
+    }
+
+    fn image(&self) -> &ImageConfig {
+        self.image.deref()
+    }
+
+    fn paths(&self) -> &PathManager {
+        self.paths.deref()
+    }
+
+    fn create_disk(config: Arc<ImageConfig>, paths: Arc<PathManager>) -> Result<Self> {
+        if !matches!(config.format, ImageFormat::Raw) {
+            return Err(BuildError::InvalidDiskType.into());
+        }
+
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(paths.final_image())?
+            .set_len(config.size as u64 * 1024u64.pow(2))?;
+
+        Ok(Self {
+            backing: paths.final_image(),
+            image: config,
+            paths,
+            device: None,
+        })
+    }
+
+    fn mount(&mut self) -> Result<()> {
+        if self.device.is_none() {
+            self.device = Some(Self::attach(&self.backing)?);
+        }
+
+        Ok(())
+    }
+
+    fn expose_partitions(&mut self) -> Result<()> {
+        let Some(dev) = self.device.as_ref() else {
+            return Err(BuildError::LoopError.into());
+        };
+
+        dev.rescan(self.image.partitions.len())?;
+        self.paths.set_block_device(Some(dev.path.clone()));
+
+        Ok(())
+    }
+
+    fn unmount(&mut self) -> Result<()> {
+        self.paths.set_block_device(None);
+
+        if let Some(dev) = self.device.take() {
+            dev.detach()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for LoopDevice {
+    fn drop(&mut self) {
+        if self.device.is_some() {
+            warn!("Loop device was not detached explicitly");
+
+            if let Err(err) = self.unmount() {
+                error!("Failed to detach loop device: {:?}", err);
+            }
+        }
+    }
+}
@@ -1,73 +1,271 @@
 use std::ffi::CString;
-use std::fs::File;
+use std::fs;
 use std::os::unix::ffi::OsStrExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::Command;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
-use log::{debug, info};
+use log::{debug, error, info, warn};
 
 use hub::BuildError;
+use hub::cancel;
 use hub::config::ImageConfig;
 use hub::paths::PathManager;
 
-pub fn mount_filesystems(image: Arc<ImageConfig>, path: Arc<PathManager>) -> hub::Result<MountHandle> {
-    let mut files = vec![];
+use crate::block::{BlockDevice, RedoxDisk, Stream};
+use crate::filesystem::{FilesystemDriver, HostDirectory, Registry, Target};
+use crate::{find_tool, DiskManager};
+
+/// Mounts the filesystem of each partition at `PathManager::live_part` through its driver.
+/// Partitions are opened through the disk manager, so this works whether or not it exposes them as files.
+/// If any filesystem fails to mount, those which were already mounted are unmounted again.
+pub fn mount_filesystems(
+    image: Arc<ImageConfig>, disk: &dyn DiskManager, drivers: &Registry, path: Arc<PathManager>,
+) -> hub::Result<MountHandle> {
+    let mut handle = MountHandle { mounts: vec![] };
 
     for part in image.partitions.iter() {
+        cancel::checkpoint()?;
+
         if let Some(fs) = part.filesystem.as_ref() {
-            let source = path.partition(&part.label).expect("No blockdev defined for partition");
+            let source = path.partition(&part.label).filter(|source| source.exists());
             let mount = path.live_part(&part.label).expect("No blockdev defined for partition");
 
-            match fs.to_lowercase().as_str() {
-                "fat32" => {
-                    info!("Mounting FAT32: {:?} => {:?}", &source, &mount);
-
-                    if let out = Command::new("fusefat")
-                        .args(["-o", "rw+"]) // fusefat is not officially stable and warns strongly against RW as errors might occur. This suppresses the error
-                        .arg(&source)
-                        .arg(&mount)
-                        .spawn()?
-                        .wait_with_output()? {
-                        if !out.status.success() {
-                            return Err(BuildError::FuseError(String::from_utf8(out.stderr)?).into());
-                        }
-                    };
-                }
-                "redoxfs" => {
-                    info!("Mounting RedoxFS: {:?} => {:?}", &source, &mount);
+            let driver = drivers.get(fs)?;
+            let target = Target {
+                partition: part,
+                paths: &path,
+                source: source.clone(),
+                device: disk.open_partition(&part.label)?,
+            };
+            let kind = driver.mount(target, &mount)?;
 
-                    let disk = redoxfs::DiskFile::open(source)?;
-                    let fs = redoxfs::FileSystem::open(disk, None, None, false)?; // TODO: forward opts to mount
+            let mount = Mount::new(part.label.clone(), source, mount, driver, kind);
 
-                    redoxfs::mount(fs, mount, |mount_path| {
-                        debug!("RedoxFS mounted on: {:?}", mount_path);
-                    })?;
-                }
-                _ => todo!()
+            match mount.driver.stat(&mount) {
+                Ok(usage) => info!("{}: {} of {} bytes free", &mount.label, usage.free, usage.capacity),
+                Err(err) => warn!("Failed to stat partition '{}': {:?}", &mount.label, err),
+            }
+
+            handle.mounts.push(mount);
+        }
+    }
+
+    Ok(handle)
+}
+
+fn is_superuser() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// The mount points and filesystem types listed in `/proc/self/mounts`, in the order they were mounted
+fn mount_table() -> Vec<(PathBuf, String)> {
+    // Whitespace in mount points is octal-escaped
+    let unescape = |field: &str| {
+        field
+            .replace("\\040", " ")
+            .replace("\\011", "\t")
+            .replace("\\012", "\n")
+            .replace("\\134", "\\")
+    };
+
+    fs::read_to_string("/proc/self/mounts")
+        .map(|mounts| {
+            mounts
+                .lines()
+                .filter_map(|line| {
+                    let mut fields = line.split(' ');
+                    let target = PathBuf::from(unescape(fields.nth(1)?));
+                    Some((target, fields.next()?.to_owned()))
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Everything mounted at or below `dir` along with its filesystem type, most recently mounted first
+pub(crate) fn mounts_under(dir: &Path) -> Vec<(PathBuf, String)> {
+    let dir = fs::canonicalize(dir).unwrap_or_else(|_| dir.to_owned());
+
+    mount_table()
+        .into_iter()
+        .rev()
+        .filter(|(mount, _)| mount.starts_with(&dir))
+        .collect()
+}
+
+/// Unmounts a filesystem left behind by an earlier build. Its driver may be gone, in which case the mount is detached lazily
+pub(crate) fn unmount_stale(target: &Path, fs_type: &str) -> hub::Result<()> {
+    let fuse = fs_type.starts_with("fuse");
+    let result = match fuse {
+        true => unmount_fuse(target, false),
+        false => unmount_kernel(target, 0),
+    };
+
+    if let Err(err) = result {
+        debug!("Failed to unmount {:?} ({:?}). Detaching it instead", target, err);
+
+        match fuse {
+            true => unmount_fuse(target, true)?,
+            false => unmount_kernel(target, libc::MNT_DETACH)?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Detaches a FUSE filesystem. Unprivileged users have to go through `fusermount`
+fn unmount_fuse(target: &Path, lazy: bool) -> hub::Result<()> {
+    if is_superuser() {
+        return unmount_kernel(target, if lazy { libc::MNT_DETACH } else { 0 });
+    }
+
+    let fusermount = find_tool("fusermount3")
+        .or(find_tool("fusermount"))
+        .ok_or(BuildError::HostToolMissing("fusermount".into()))?;
+
+    let out = Command::new(fusermount)
+        .arg(if lazy { "-uz" } else { "-u" })
+        .arg(target)
+        .output()?;
+    if !out.status.success() {
+        return Err(BuildError::FuseError(String::from_utf8(out.stderr)?).into());
+    }
+
+    Ok(())
+}
+
+fn unmount_kernel(target: &Path, flags: libc::c_int) -> hub::Result<()> {
+    let cstr = CString::new(target.as_os_str().as_bytes())?;
+
+    if unsafe { libc::umount2(cstr.as_ptr(), flags) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    Ok(())
+}
+
+/// How a filesystem was mounted, which determines how it is torn down
+pub enum MountKind {
+    /// A RedoxFS filesystem opened in-process. Changes are written through its transactions as they're made
+    RedoxFS(Mutex<redoxfs::FileSystem<RedoxDisk>>),
+    /// A FAT filesystem opened in-process. Its device is kept alongside, as fatfs doesn't hand it back once unmounted
+    Fat {
+        fs: Mutex<fatfs::FileSystem<Stream>>,
+        device: Arc<dyn BlockDevice>,
+    },
+    /// Mounted by something outside the builder, such as a user-defined filesystem's script
+    External,
+    /// Not a mount at all, but a directory on the host which files are staged in
+    Directory,
+}
+
+impl MountKind {
+    /// Unmounts the filesystem at `target`
+    pub fn release(self, target: &Path) -> hub::Result<()> {
+        match self {
+            MountKind::Fat { fs, device } => {
+                fs.into_inner().expect("FAT lock poisoned").unmount()?;
+                device.flush()
+            }
+            MountKind::Directory => Ok(()),
+            MountKind::RedoxFS(fs) => {
+                let fs = fs.into_inner().expect("RedoxFS lock poisoned");
+                fs.disk.0.flush()
+            }
+            // Whatever is mounted there has to be detached without knowing how it got there
+            MountKind::External => {
+                let canonical = fs::canonicalize(target).unwrap_or_else(|_| target.to_owned());
+                let fs_type = mount_table()
+                    .into_iter()
+                    .rev()
+                    .find(|(mount, _)| mount == &canonical)
+                    .map(|(_, fs_type)| fs_type)
+                    .unwrap_or_default();
+
+                unmount_stale(target, &fs_type)
             }
         }
     }
+}
 
-    Ok(MountHandle {
-        partitions: files
-    })
+pub struct Mount {
+    pub label: String,
+    /// The partition's file or block device, if the disk manager exposes it through the filesystem
+    pub source: Option<PathBuf>,
+    pub target: PathBuf,
+    pub driver: Arc<dyn FilesystemDriver>,
+    kind: MountKind,
 }
 
+impl Mount {
+    pub(crate) fn new(
+        label: String, source: Option<PathBuf>, target: PathBuf, driver: Arc<dyn FilesystemDriver>, kind: MountKind,
+    ) -> Self {
+        Self { label, source, target, driver, kind }
+    }
+
+    /// Treats a directory on the host like a mounted filesystem, so files can be placed into it
+    pub fn directory(label: String, target: PathBuf) -> Self {
+        Self {
+            label,
+            source: None,
+            target,
+            driver: Arc::new(HostDirectory),
+            kind: MountKind::Directory,
+        }
+    }
+
+    pub fn kind(&self) -> &MountKind {
+        &self.kind
+    }
+
+    fn unmount(self) -> hub::Result<()> {
+        debug!("Unmounting {:?}", &self.target);
+        self.driver.unmount(self.source.as_deref(), &self.target, self.kind)
+    }
+}
+
+/// Tracks every filesystem mounted for the build. They are unmounted in reverse order when the handle is dropped.
 pub struct MountHandle {
-    partitions: Vec<PathBuf>,
+    mounts: Vec<Mount>,
 }
 
 impl MountHandle {
-    fn unmount_all(self) -> hub::Result<()> {
-        for part in self.partitions {
-            let cstr = CString::new(part.into_os_string().as_bytes())?;
+    pub fn mounts(&self) -> &[Mount] {
+        &self.mounts
+    }
+
+    /// The mount of a particular partition
+    pub fn mount(&self, label: &str) -> Option<&Mount> {
+        self.mounts.iter().find(|mount| mount.label == label)
+    }
+
+    /// Unmounts every filesystem, most recently mounted first. All are attempted even if some fail, in which case the
+    /// first error is returned.
+    pub fn unmount_all(&mut self) -> hub::Result<()> {
+        let mut result = Ok(());
 
-            if unsafe { libc::umount2(cstr.as_ptr(), 0) } != 0 {
-                return Err(std::io::Error::last_os_error().into());
+        while let Some(mount) = self.mounts.pop() {
+            let label = mount.label.clone();
+
+            if let Err(err) = mount.unmount() {
+                error!("Failed to unmount partition '{}': {:?}", label, err);
+
+                if result.is_ok() {
+                    result = Err(err);
+                }
             }
         }
 
-        Ok(())
+        result
+    }
+}
+
+impl Drop for MountHandle {
+    fn drop(&mut self) {
+        if !self.mounts.is_empty() {
+            let _ = self.unmount_all();
+        }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,480 @@
+use std::io::{Read, Write};
+use std::ops::Deref;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use log::{debug, error, warn};
+
+use hub::config::ImageConfig;
+use hub::error::*;
+use hub::paths::PathManager;
+
+use crate::block::{BlockDevice, Slice};
+use crate::DiskManager;
+use crate::gpt;
+use crate::qemu::{create_image, Export, QemuProc};
+
+const NBD_MAGIC: u64 = 0x4e42444d41474943; // "NBDMAGIC"
+const IHAVEOPT: u64 = 0x49484156454F5054; // "IHAVEOPT"
+const REPLY_MAGIC: u64 = 0x3e889045565a9;
+const REQUEST_MAGIC: u32 = 0x25609513;
+const SIMPLE_REPLY_MAGIC: u32 = 0x67446698;
+
+const FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const FLAG_NO_ZEROES: u16 = 1 << 1;
+
+const OPT_GO: u32 = 7;
+const REP_ACK: u32 = 1;
+const REP_INFO: u32 = 3;
+const REP_FLAG_ERROR: u32 = 1 << 31;
+const INFO_EXPORT: u16 = 0;
+
+const CMD_READ: u16 = 0;
+const CMD_WRITE: u16 = 1;
+const CMD_DISC: u16 = 2;
+const CMD_FLUSH: u16 = 3;
+
+/// Largest payload sent in a single request. Servers may reject anything above 32MiB.
+const MAX_REQUEST: usize = 16 * 1024 * 1024;
+
+/// The name the storage daemon exports the disk under
+const EXPORT_NAME: &str = "disk";
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A minimal userspace NBD client speaking the fixed-newstyle handshake and simple replies.
+/// Requests are serialised over a single connection.
+pub struct NbdClient {
+    socket: Mutex<UnixStream>,
+    size: u64,
+    handle: AtomicU64,
+}
+
+fn read_u16(socket: &mut UnixStream) -> Result<u16> {
+    let mut buf = [0u8; 2];
+    socket.read_exact(&mut buf)?;
+    Ok(u16::from_be_bytes(buf))
+}
+
+fn read_u32(socket: &mut UnixStream) -> Result<u32> {
+    let mut buf = [0u8; 4];
+    socket.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_u64(socket: &mut UnixStream) -> Result<u64> {
+    let mut buf = [0u8; 8];
+    socket.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+impl NbdClient {
+    pub fn connect<P: AsRef<Path>>(path: P, export: &str) -> Result<Self> {
+        let start = Instant::now();
+        let mut socket = loop {
+            match UnixStream::connect(path.as_ref()) {
+                Ok(socket) => break socket,
+                Err(err) if start.elapsed() < CONNECT_TIMEOUT => {
+                    debug!("Waiting for NBD server: {}", err);
+                    std::thread::sleep(Duration::from_millis(50));
+                }
+                Err(err) => return Err(err.into()),
+            }
+        };
+
+        if read_u64(&mut socket)? != NBD_MAGIC || read_u64(&mut socket)? != IHAVEOPT {
+            return Err(BuildError::NbdError("Server is not speaking newstyle NBD".into()).into());
+        }
+
+        let server_flags = read_u16(&mut socket)?;
+        if server_flags & FLAG_FIXED_NEWSTYLE == 0 {
+            return Err(BuildError::NbdError("Server does not support fixed newstyle negotiation".into()).into());
+        }
+
+        let client_flags = (server_flags & (FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES)) as u32;
+        socket.write_all(&client_flags.to_be_bytes())?;
+
+        let mut option = Vec::new();
+        option.extend_from_slice(&IHAVEOPT.to_be_bytes());
+        option.extend_from_slice(&OPT_GO.to_be_bytes());
+        option.extend_from_slice(&(4 + export.len() as u32 + 2).to_be_bytes());
+        option.extend_from_slice(&(export.len() as u32).to_be_bytes());
+        option.extend_from_slice(export.as_bytes());
+        option.extend_from_slice(&0u16.to_be_bytes()); // No additional info requests
+        socket.write_all(&option)?;
+
+        let mut size = None;
+        loop {
+            if read_u64(&mut socket)? != REPLY_MAGIC || read_u32(&mut socket)? != OPT_GO {
+                return Err(BuildError::NbdError("Malformed option reply".into()).into());
+            }
+
+            let reply = read_u32(&mut socket)?;
+            let mut data = vec![0u8; read_u32(&mut socket)? as usize];
+            socket.read_exact(&mut data)?;
+
+            match reply {
+                REP_ACK => break,
+                REP_INFO if data.len() >= 10 && u16::from_be_bytes([data[0], data[1]]) == INFO_EXPORT => {
+                    size = Some(u64::from_be_bytes(data[2..10].try_into().expect("Slice is 8 bytes")));
+                }
+                REP_INFO => continue,
+                err if err & REP_FLAG_ERROR != 0 => {
+                    return Err(BuildError::NbdError(format!(
+                        "Server refused export '{}' ({:#x}): {}",
+                        export,
+                        err,
+                        String::from_utf8_lossy(&data)
+                    ))
+                    .into());
+                }
+                other => return Err(BuildError::NbdError(format!("Unexpected option reply {:#x}", other)).into()),
+            }
+        }
+
+        let size = size.ok_or(BuildError::NbdError("Server did not report the export size".into()))?;
+        debug!("Connected to NBD export '{}' ({} bytes)", export, size);
+
+        Ok(Self {
+            socket: Mutex::new(socket),
+            size,
+            handle: AtomicU64::new(0),
+        })
+    }
+
+    fn request(&self, command: u16, offset: u64, len: u32, payload: &[u8], response: &mut [u8]) -> Result<()> {
+        let handle = self.handle.fetch_add(1, Ordering::Relaxed);
+
+        let mut request = Vec::with_capacity(28 + payload.len());
+        request.extend_from_slice(&REQUEST_MAGIC.to_be_bytes());
+        request.extend_from_slice(&0u16.to_be_bytes());
+        request.extend_from_slice(&command.to_be_bytes());
+        request.extend_from_slice(&handle.to_be_bytes());
+        request.extend_from_slice(&offset.to_be_bytes());
+        request.extend_from_slice(&len.to_be_bytes());
+        request.extend_from_slice(payload);
+
+        let mut socket = self.socket.lock().map_err(|_| BuildError::NbdError("Connection poisoned".into()))?;
+        socket.write_all(&request)?;
+
+        if command == CMD_DISC {
+            return Ok(());
+        }
+
+        if read_u32(&mut socket)? != SIMPLE_REPLY_MAGIC {
+            return Err(BuildError::NbdError("Malformed reply".into()).into());
+        }
+
+        let errno = read_u32(&mut socket)?;
+        if read_u64(&mut socket)? != handle {
+            return Err(BuildError::NbdError("Reply does not match request".into()).into());
+        }
+
+        if errno != 0 {
+            return Err(std::io::Error::from_raw_os_error(errno as i32).into());
+        }
+
+        socket.read_exact(response)?;
+        Ok(())
+    }
+
+    pub fn disconnect(&self) -> Result<()> {
+        self.request(CMD_DISC, 0, 0, &[], &mut [])
+    }
+}
+
+impl BlockDevice for NbdClient {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let len = self.size.saturating_sub(offset).min(buf.len().min(MAX_REQUEST) as u64) as usize;
+        if len > 0 {
+            self.request(CMD_READ, offset, len as u32, &[], &mut buf[..len])?;
+        }
+
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize> {
+        let len = self.size.saturating_sub(offset).min(buf.len().min(MAX_REQUEST) as u64) as usize;
+        if len == 0 && !buf.is_empty() {
+            return Err(std::io::Error::from_raw_os_error(libc::ENOSPC).into());
+        }
+
+        self.request(CMD_WRITE, offset, len as u32, &buf[..len], &mut [])?;
+        Ok(len)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.request(CMD_FLUSH, 0, 0, &[], &mut [])
+    }
+
+    fn size(&self) -> Result<u64> {
+        Ok(self.size)
+    }
+}
+
+/// Exports the image from `qemu-storage-daemon` over NBD on a unix socket.
+/// Partitions are accessed in-process through a userspace NBD client, so neither FUSE nor kernel mounts are required.
+/// Since there is no file or block device to hand to libparted, the partition table is written directly.
+pub struct Nbd {
+    backing: PathBuf,
+    image: Arc<ImageConfig>,
+    paths: Arc<PathManager>,
+    proc: Option<QemuProc>,
+    client: Option<Arc<NbdClient>>,
+    partitions: Vec<(String, u64, u64)>,
+}
+
+impl Nbd {
+    fn socket(&self) -> PathBuf {
+        self.paths.nbd_socket()
+    }
+
+    fn client(&self) -> Result<Arc<NbdClient>> {
+        self.client
+            .as_ref()
+            .map(Arc::clone)
+            .ok_or(BuildError::NbdError("Disk is not mounted".into()).into())
+    }
+}
+
+impl DiskManager for Nbd {
+    fn backing(&self) -> PathBuf {
+        self.backing.clone()
+    }
+
+    fn image(&self) -> &ImageConfig {
+        self.image.deref()
+    }
+
+    fn paths(&self) -> &PathManager {
+        self.paths.deref()
+    }
+
+    fn create_disk(config: Arc<ImageConfig>, path: Arc<PathManager>) -> Result<Self> {
+        create_image(&config, &path)?;
+
+        Ok(Self {
+            backing: path.final_image(),
+            image: config,
+            paths: path,
+            proc: None,
+            client: None,
+            partitions: vec![],
+        })
+    }
+
+    fn mount(&mut self) -> Result<()> {
+        let socket = self.socket();
+        self.proc = Some(QemuProc::init(&self.paths, self.image.format, Export::Nbd(socket.clone(), EXPORT_NAME.into()))?);
+        self.client = Some(Arc::new(NbdClient::connect(socket, EXPORT_NAME)?));
+        debug!("Connected to storage daemon over NBD");
+
+        Ok(())
+    }
+
+    fn build(&mut self) -> Result<()> {
+        let client = self.client()?;
+        let sectors = client.size()? / gpt::SECTOR_SIZE;
+        let (first, last) = gpt::usable_range(sectors)?;
+
+        let partitions = crate::layout(&self.image, gpt::SECTOR_SIZE as i64)?
+            .into_iter()
+            .zip(self.image.partitions.iter())
+            .map(|((start, len), partition)| {
+                let end = ((start + len - 1) as u64).min(last);
+                let start = (start as u64).max(first);
+
+                Ok(gpt::GptPartition {
+                    type_guid: gpt::parse_guid(match partition.filesystem.as_deref() {
+                        Some(fs) if fs.starts_with("fat") => gpt::BASIC_DATA,
+                        _ => gpt::LINUX_DATA,
+                    })
+                    .expect("Well-known GUIDs are valid"),
+                    guid: gpt::random_guid()?,
+                    first_lba: start,
+                    last_lba: end,
+                    attributes: 0,
+                    name: partition.label.clone(),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        gpt::write(client.as_ref(), &partitions)?;
+
+        self.partitions = partitions
+            .into_iter()
+            .map(|part| {
+                (
+                    part.name,
+                    part.first_lba * gpt::SECTOR_SIZE,
+                    (part.last_lba + 1 - part.first_lba) * gpt::SECTOR_SIZE,
+                )
+            })
+            .collect();
+
+        Ok(())
+    }
+
+    // Partitions are only reachable through `open_partition`
+    fn expose_partitions(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn open_partition(&self, label: &str) -> Result<Box<dyn BlockDevice>> {
+        let (_, offset, len) = self
+            .partitions
+            .iter()
+            .find(|(name, ..)| name.eq(label))
+            .ok_or(BuildError::InvalidPartitionName)?;
+
+        Ok(Box::new(Slice::new(self.client()?, *offset, *len)))
+    }
+
+    fn unmount(&mut self) -> Result<()> {
+        // The daemon is killed even if the export couldn't be flushed, so that it doesn't outlive the build
+        let closed = match self.client.take() {
+            Some(client) => client.flush().and_then(|_| client.disconnect()),
+            None => Ok(()),
+        };
+
+        debug!("Killing Storage Daemon");
+        if let Some(proc) = self.proc.take() {
+            proc.kill()?;
+        }
+
+        closed
+    }
+}
+
+impl Drop for Nbd {
+    fn drop(&mut self) {
+        if self.proc.is_some() {
+            warn!("NBD export was not shut down explicitly");
+
+            if let Err(err) = self.unmount() {
+                error!("Failed to shut down storage daemon: {:?}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixListener;
+    use std::thread::JoinHandle;
+
+    use hub::global::Inner;
+
+    use super::*;
+
+    /// Serves `disk` as the export `name` to a single client, returning the disk once the client disconnects
+    fn serve(test: &str, name: &'static str, mut disk: Vec<u8>) -> (PathBuf, JoinHandle<Vec<u8>>) {
+        let path = std::env::temp_dir().join(format!("redox-builder-{}-{}.sock", test, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let server = std::thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+
+            socket.write_all(&NBD_MAGIC.to_be_bytes()).unwrap();
+            socket.write_all(&IHAVEOPT.to_be_bytes()).unwrap();
+            socket.write_all(&(FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES).to_be_bytes()).unwrap();
+            assert_eq!(read_u32(&mut socket).unwrap(), (FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES) as u32);
+
+            assert_eq!(read_u64(&mut socket).unwrap(), IHAVEOPT);
+            assert_eq!(read_u32(&mut socket).unwrap(), OPT_GO);
+            let mut option = vec![0; read_u32(&mut socket).unwrap() as usize];
+            socket.read_exact(&mut option).unwrap();
+
+            let len = u32::from_be_bytes(option[0..4].try_into().unwrap()) as usize;
+            assert_eq!(option.len(), 4 + len + 2);
+
+            let reply = |socket: &mut UnixStream, kind: u32, data: &[u8]| {
+                socket.write_all(&REPLY_MAGIC.to_be_bytes()).unwrap();
+                socket.write_all(&OPT_GO.to_be_bytes()).unwrap();
+                socket.write_all(&kind.to_be_bytes()).unwrap();
+                socket.write_all(&(data.len() as u32).to_be_bytes()).unwrap();
+                socket.write_all(data).unwrap();
+            };
+
+            if &option[4..4 + len] != name.as_bytes() {
+                reply(&mut socket, REP_FLAG_ERROR | 6, b"No such export");
+                return disk;
+            }
+
+            let mut info = INFO_EXPORT.to_be_bytes().to_vec();
+            info.extend_from_slice(&(disk.len() as u64).to_be_bytes());
+            info.extend_from_slice(&0u16.to_be_bytes());
+            reply(&mut socket, REP_INFO, &info);
+            reply(&mut socket, REP_ACK, &[]);
+
+            loop {
+                assert_eq!(read_u32(&mut socket).unwrap(), REQUEST_MAGIC);
+                read_u16(&mut socket).unwrap();
+                let command = read_u16(&mut socket).unwrap();
+                let handle = read_u64(&mut socket).unwrap();
+                let offset = read_u64(&mut socket).unwrap() as usize;
+                let len = read_u32(&mut socket).unwrap() as usize;
+
+                if command == CMD_DISC {
+                    return disk;
+                }
+
+                if command == CMD_WRITE {
+                    socket.read_exact(&mut disk[offset..offset + len]).unwrap();
+                }
+
+                socket.write_all(&SIMPLE_REPLY_MAGIC.to_be_bytes()).unwrap();
+                socket.write_all(&0u32.to_be_bytes()).unwrap();
+                socket.write_all(&handle.to_be_bytes()).unwrap();
+
+                if command == CMD_READ {
+                    socket.write_all(&disk[offset..offset + len]).unwrap();
+                }
+            }
+        });
+
+        (path, server)
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        let (path, server) = serve("nbd", "disk", vec![0xAA; 4096]);
+        let client = NbdClient::connect(&path, "disk").unwrap();
+
+        assert_eq!(client.size().unwrap(), 4096);
+
+        client.write_all_at(100, b"hello").unwrap();
+        client.flush().unwrap();
+
+        let mut buf = [0; 7];
+        client.read_exact_at(99, &mut buf).unwrap();
+        assert_eq!(&buf, b"\xAAhello\xAA");
+
+        // Accesses are cut short at the end of the export
+        let mut buf = [0; 8];
+        assert_eq!(client.read_at(4092, &mut buf).unwrap(), 4);
+        assert!(client.write_at(4096, b"x").is_err());
+
+        client.disconnect().unwrap();
+        let disk = server.join().unwrap();
+        assert_eq!(&disk[100..105], b"hello");
+        assert!(disk[..100].iter().chain(&disk[105..]).all(|byte| *byte == 0xAA));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn refused_exports_are_reported() {
+        let (path, server) = serve("nbd-refused", "disk", vec![0; 512]);
+        let err = NbdClient::connect(&path, "other").err().unwrap();
+
+        assert!(matches!(err.inner(), Inner::BuildError(BuildError::NbdError(_))));
+
+        server.join().unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,479 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{debug, info};
+
+use hub::config::{ConfigFile, File, FilesystemEntry, ImageConfig, Lock, LockedArtifact, LockedComponent, Partition};
+use hub::error::*;
+use hub::paths::PathManager;
+use hub::{cancel, shell};
+
+use crate::filesystem::{components, Metadata};
+use crate::mnt::{Mount, MountHandle};
+use crate::template;
+use crate::tree::{EntryKind, Filter, Tree};
+
+/// Writes the partition's `[[file]]` entries into its filesystem, in the order they're listed
+pub fn place_files(config: &ConfigFile, partition: &Partition, mounts: &MountHandle, paths: &PathManager) -> Result<()> {
+    if partition.files.is_empty() {
+        return Ok(());
+    }
+
+    if partition.filesystem.is_none() {
+        return Err(BuildError::NoFilesystem(partition.label.clone()).into());
+    }
+
+    let mount = mounts
+        .mount(&partition.label)
+        .ok_or(BuildError::NoPartitionMountPoint(partition.label.clone()))?;
+
+    info!("Placing {} file(s) into '{}'", partition.files.len(), &partition.label);
+
+    place_entries(config, &partition.files, mount, paths)
+}
+
+/// Writes `[[file]]` entries into a mounted filesystem, in the order they're listed
+pub fn place_entries(config: &ConfigFile, files: &[File], mount: &Mount, paths: &PathManager) -> Result<()> {
+    let default_mtime = default_mtime(&config.image);
+    let mut owners = Owners { mount, passwd: None, group: None };
+
+    for file in files.iter() {
+        // Paths are always relative to the root of the filesystem, so they may not climb out of it
+        if components(&file.path)?.is_empty() {
+            return Err(BuildError::InvalidFilePath(file.path.clone()).into());
+        }
+
+        debug!("Placing {:?}", &file.path);
+
+        let placed = match &file.content {
+            FilesystemEntry::Text(text) => {
+                mount.driver.write_file(mount, &file.path, text.as_bytes())?;
+                vec![]
+            }
+            FilesystemEntry::Template(template) => {
+                let text = template::render(template, config, paths)?;
+                mount.driver.write_file(mount, &file.path, text.as_bytes())?;
+                vec![]
+            }
+            FilesystemEntry::Symlink(target) => {
+                mount.driver.symlink(mount, &file.path, target)?;
+                vec![]
+            }
+            FilesystemEntry::Mkdir(true) => {
+                mount.driver.create_dir(mount, &file.path)?;
+                vec![]
+            }
+            // There's nothing to place, nor anything to apply the entry's metadata to
+            FilesystemEntry::Mkdir(false) => return Err(BuildError::InvalidFilePath(file.path.clone()).into()),
+            FilesystemEntry::Artifact(artifact) => {
+                let source = paths
+                    .artifact(artifact)
+                    .filter(|path| path.is_file())
+                    .ok_or(BuildError::MissingArtifact(artifact.clone()))?;
+
+                mount.driver.write_file(mount, &file.path, &fs::read(source)?)?;
+                vec![]
+            }
+            FilesystemEntry::Shell(script) => {
+                let output = shell::call_raw(paths, script, "main", &[shell::Arg::Path(Path::new("/").join(&file.path))])?;
+                mount.driver.write_file(mount, &file.path, &output)?;
+                vec![]
+            }
+            FilesystemEntry::Directory(source) => {
+                let filter = Filter::new(&file.include, &file.exclude)?;
+                let tree = Tree::walk(&source_path(paths, source, Path::is_dir)?, &filter)?;
+                place_tree(mount, &file.path, tree, &filter)?
+            }
+            FilesystemEntry::Archive(source) => {
+                let filter = Filter::new(&file.include, &file.exclude)?;
+                let tree = Tree::extract(&source_path(paths, source, Path::is_file)?, &paths.archives())?;
+                place_tree(mount, &file.path, tree, &filter)?
+            }
+        };
+
+        let uid = match (file.uid, file.user.as_ref()) {
+            (Some(_), Some(_)) => return Err(BuildError::ConflictingOwner(file.path.clone()).into()),
+            (Some(uid), None) => Some(uid),
+            (None, Some(user)) => Some(owners.uid(user)?),
+            (None, None) => None,
+        };
+        let gid = match (file.gid, file.group.as_ref()) {
+            (Some(_), Some(_)) => return Err(BuildError::ConflictingOwner(file.path.clone()).into()),
+            (Some(gid), None) => Some(gid),
+            (None, Some(group)) => Some(owners.gid(group)?),
+            (None, None) => None,
+        };
+        let mtime = Some(file.mtime.map(from_millis).unwrap_or(default_mtime));
+
+        let metadata = Metadata {
+            // Links have no permissions of their own
+            mode: file.mode.filter(|_| !matches!(file.content, FilesystemEntry::Symlink(_))),
+            uid,
+            gid,
+            mtime,
+        };
+
+        mount.driver.set_metadata(mount, &file.path, &metadata)?;
+
+        // Everything inside a tree keeps its own permissions, but takes its owner and modification time from the entry
+        for (path, mode) in placed {
+            mount.driver.set_metadata(mount, &path, &Metadata { mode, uid, gid, mtime })?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes the resolved config and its `Lock` to `ImageConfig::embed`, if it's set. Placed after all other files, so
+/// neither can be overwritten by them.
+pub fn embed_config(config: &ConfigFile, mounts: &MountHandle, paths: &PathManager) -> Result<()> {
+    let Some(embed) = config.image.embed.as_ref() else {
+        return Ok(());
+    };
+
+    for path in [&embed.path, &embed.lock] {
+        if components(path)?.is_empty() {
+            return Err(BuildError::InvalidFilePath(path.clone()).into());
+        }
+    }
+
+    let mount = mounts
+        .mount(&embed.partition)
+        .ok_or(BuildError::NoPartitionMountPoint(embed.partition.clone()))?;
+
+    info!("Embedding config at {:?} and its lock at {:?} in '{}'", &embed.path, &embed.lock, &embed.partition);
+
+    let lock = lock(config, paths)?.serialise()?;
+
+    for (path, contents) in [(&embed.path, config.resolved()?), (&embed.lock, lock)] {
+        mount.driver.write_file(mount, path, contents.as_bytes())?;
+        mount.driver.set_metadata(mount, path, &Metadata {
+            mode: Some(0o644),
+            uid: None,
+            gid: None,
+            mtime: Some(default_mtime(&config.image)),
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Records the version of each component and hashes everything it emitted into the artifact store
+fn lock(config: &ConfigFile, paths: &PathManager) -> Result<Lock> {
+    let mut lock = Lock::default();
+
+    for component in config.components.iter() {
+        let dir = paths.artifacts().join(&component.name);
+        let mut artifacts = vec![];
+
+        // Components which weren't built have nothing in the artifact store
+        if dir.is_dir() {
+            for entry in Tree::walk(&dir, &Filter::new(&[], &[])?)?.entries {
+                if let EntryKind::HostFile(source) = entry.kind {
+                    artifacts.push(LockedArtifact { path: entry.path, sha256: template::sha256(&source)? });
+                }
+            }
+        }
+
+        lock.components.push(LockedComponent {
+            name: component.name.clone(),
+            version: component.version.clone(),
+            artifacts,
+        });
+    }
+
+    Ok(lock)
+}
+
+/// Resolves the source of a `directory` or `archive` entry, which is either an artifact reference or a path on the host
+fn source_path(paths: &PathManager, source: &str, exists: impl Fn(&Path) -> bool) -> Result<PathBuf> {
+    match paths.artifact(source) {
+        Some(artifact) if exists(&artifact) => Ok(artifact),
+        Some(_) => Err(BuildError::MissingArtifact(source.to_owned()).into()),
+        None => Ok(PathBuf::from(source)),
+    }
+}
+
+/// Places the accepted entries of a tree below `root`, returning where each was placed along with its permissions.
+/// Entries below a symlink of the same tree are refused, as they'd be placed wherever the link happens to point.
+fn place_tree(mount: &Mount, root: &Path, tree: Tree, filter: &Filter) -> Result<Vec<(PathBuf, Option<u32>)>> {
+    mount.driver.create_dir(mount, root)?;
+
+    let mut placed = vec![];
+    let mut links = HashSet::new();
+
+    for entry in tree.entries.into_iter().filter(|entry| filter.accepts(entry)) {
+        cancel::checkpoint()?;
+
+        let path = root.join(&entry.path);
+        if entry.path.ancestors().skip(1).any(|parent| links.contains(parent)) {
+            return Err(BuildError::InvalidFilePath(path).into());
+        }
+        let mut mode = entry.mode;
+
+        match entry.kind {
+            EntryKind::Dir => mount.driver.create_dir(mount, &path)?,
+            EntryKind::HostFile(source) => mount.driver.write_file(mount, &path, &fs::read(source)?)?,
+            EntryKind::Symlink(target) => {
+                mode = None;
+                links.insert(entry.path.clone());
+                mount.driver.symlink(mount, &path, &target)?
+            }
+        }
+
+        placed.push((path, mode));
+    }
+
+    debug!("Placed {} entries below {:?}", placed.len(), root);
+
+    Ok(placed)
+}
+
+fn from_millis(ms: u64) -> (u64, u32) {
+    (ms / 1000, (ms % 1000) as u32 * 1_000_000)
+}
+
+/// The modification time of files which don't set their own, so that builds are reproducible if it's pinned
+pub(crate) fn default_mtime(image: &ImageConfig) -> (u64, u32) {
+    default_mtime_from(image, std::env::var("SOURCE_DATE_EPOCH").ok().as_deref())
+}
+
+/// Like `default_mtime`, but given the value of `$SOURCE_DATE_EPOCH` rather than reading it from the environment
+fn default_mtime_from(image: &ImageConfig, source_date_epoch: Option<&str>) -> (u64, u32) {
+    if let Some(mtime) = image.mtime {
+        return from_millis(mtime);
+    }
+
+    if let Some(epoch) = source_date_epoch.and_then(|epoch| epoch.trim().parse().ok()) {
+        return (epoch, 0);
+    }
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    (now.as_secs(), now.subsec_nanos())
+}
+
+/// Resolves user and group names against `/etc/passwd` and `/etc/group` in the partition being populated.
+/// Each is read the first time it's needed, so it has to be placed before any file referring to it by name.
+struct Owners<'a> {
+    mount: &'a Mount,
+    passwd: Option<String>,
+    group: Option<String>,
+}
+
+impl Owners<'_> {
+    fn uid(&mut self, user: &str) -> Result<u32> {
+        let passwd = read_table(self.mount, "/etc/passwd", &mut self.passwd)?;
+
+        // Redox omits the password field from `/etc/passwd`, so the UID comes second rather than third
+        find_id(passwd, user, if passwd.contains(';') { 1 } else { 2 })
+            .ok_or(BuildError::UnknownOwner(user.to_owned()).into())
+    }
+
+    fn gid(&mut self, group: &str) -> Result<u32> {
+        let groups = read_table(self.mount, "/etc/group", &mut self.group)?;
+
+        find_id(groups, group, 2).ok_or(BuildError::UnknownOwner(group.to_owned()).into())
+    }
+}
+
+fn read_table<'a>(mount: &Mount, path: &str, cache: &'a mut Option<String>) -> Result<&'a str> {
+    if cache.is_none() {
+        let table = mount.driver.read_file(mount, Path::new(path))?;
+        *cache = Some(String::from_utf8(table)?);
+    }
+
+    Ok(cache.as_deref().unwrap_or_default())
+}
+
+/// Looks up a name in a passwd-style table. Redox separates fields with `;` while Unix uses `:`
+fn find_id(table: &str, name: &str, field: usize) -> Option<u32> {
+    table.lines().find_map(|line| {
+        let fields = line.split([';', ':']).collect::<Vec<_>>();
+
+        match fields.first() {
+            Some(entry) if *entry == name => fields.get(field)?.trim().parse().ok(),
+            _ => None,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use hub::global::Inner;
+
+    use super::*;
+
+    /// An empty directory for a test to place files into
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("redox-builder-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn config() -> ConfigFile {
+        serde_json::from_str(r#"{ "name": "test", "image": { "label": "test", "size": 1 } }"#).unwrap()
+    }
+
+    fn file(path: &str, content: FilesystemEntry) -> File {
+        File {
+            path: PathBuf::from(path),
+            mode: None,
+            uid: None,
+            gid: None,
+            user: None,
+            group: None,
+            mtime: None,
+            include: vec![],
+            exclude: vec![],
+            content,
+        }
+    }
+
+    fn place(dir: &Path, files: &[File]) -> Result<()> {
+        let config = config();
+        let paths = PathManager::new(std::sync::Arc::new(config.clone()), Some(dir.join("build")));
+
+        place_entries(&config, files, &Mount::directory("test".into(), dir.join("mount")), &paths)
+    }
+
+    #[test]
+    fn ids_are_found_in_redox_and_unix_tables() {
+        let redox = "root;0;0;root;/root;/bin/ion\nuser;1000;1000;user;/home/user;/bin/ion";
+        let unix = "root:x:0:0:root:/root:/bin/sh\nuser:x:1000:100:user:/home/user:/bin/sh";
+
+        assert_eq!(find_id(redox, "user", 1), Some(1000));
+        assert_eq!(find_id(unix, "user", 2), Some(1000));
+        assert_eq!(find_id(unix, "user", 3), Some(100));
+        assert_eq!(find_id(redox, "nobody", 1), None);
+        // Names must match exactly, not just be a prefix of the entry's name
+        assert_eq!(find_id(redox, "use", 1), None);
+        assert_eq!(find_id("user;abc", "user", 1), None);
+    }
+
+    #[test]
+    fn milliseconds_are_split_into_seconds_and_nanoseconds() {
+        assert_eq!(from_millis(0), (0, 0));
+        assert_eq!(from_millis(1_700_000_000_999), (1_700_000_000, 999_000_000));
+    }
+
+    #[test]
+    fn configured_mtime_takes_precedence_over_source_date_epoch() {
+        let mut image = config().image.as_ref().clone();
+        assert_eq!(default_mtime_from(&image, Some(" 1234 ")), (1234, 0));
+
+        image.mtime = Some(5_500);
+        assert_eq!(default_mtime_from(&image, Some("1234")), (5, 500_000_000));
+        assert_eq!(default_mtime_from(&image, None), (5, 500_000_000));
+    }
+
+    #[test]
+    fn build_time_is_used_without_a_valid_source_date_epoch() {
+        let image = config().image.as_ref().clone();
+
+        assert!(default_mtime_from(&image, Some("yesterday")).0 > 1234);
+        assert!(default_mtime_from(&image, None).0 > 1234);
+    }
+
+    #[test]
+    fn locks_hash_every_artifact_of_each_component() {
+        let dir = scratch("lock");
+        let config: ConfigFile = serde_json::from_str(r#"{
+            "name": "test",
+            "image": { "label": "test", "size": 1 },
+            "component": [
+                { "name": "kernel", "version": "0.4.1", "shell": "", "yields": ["kernel"] },
+                { "name": "unbuilt", "shell": "" }
+            ]
+        }"#).unwrap();
+        let paths = PathManager::new(std::sync::Arc::new(config.clone()), Some(dir.join("build")));
+
+        fs::create_dir_all(paths.artifacts().join("kernel/debug")).unwrap();
+        fs::write(paths.artifacts().join("kernel/kernel"), b"test").unwrap();
+        fs::write(paths.artifacts().join("kernel/debug/kernel.sym"), b"").unwrap();
+
+        let lock = lock(&config, &paths).unwrap();
+        let components = lock.components.iter().map(|component| component.name.as_str()).collect::<Vec<_>>();
+        let artifacts = lock.components[0]
+            .artifacts
+            .iter()
+            .map(|artifact| (artifact.path.to_str().unwrap(), artifact.sha256.as_str()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(components, ["kernel", "unbuilt"]);
+        assert_eq!(lock.components[0].version.as_deref(), Some("0.4.1"));
+        assert_eq!(artifacts, [
+            ("debug/kernel.sym", "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"),
+            ("kernel", "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"),
+        ]);
+        assert!(lock.components[1].artifacts.is_empty());
+        assert!(lock.serialise().unwrap().contains("[[component.artifact]]"));
+    }
+
+    #[test]
+    fn archive_symlinks_are_not_followed() {
+        let dir = scratch("archive-symlink");
+        let outside = dir.join("outside");
+        fs::create_dir_all(&outside).unwrap();
+
+        let archive = dir.join("escape.tar");
+        let mut builder = tar::Builder::new(fs::File::create(&archive).unwrap());
+
+        let mut link = tar::Header::new_gnu();
+        link.set_entry_type(tar::EntryType::Symlink);
+        link.set_size(0);
+        builder.append_link(&mut link, "a", &outside).unwrap();
+
+        let mut data = tar::Header::new_gnu();
+        data.set_size(5);
+        data.set_mode(0o644);
+        builder.append_data(&mut data, "a/x", &b"owned"[..]).unwrap();
+        builder.into_inner().unwrap();
+
+        let result = place(&dir, &[file("/root", FilesystemEntry::Archive(archive.to_string_lossy().into_owned()))]);
+
+        assert!(matches!(result.unwrap_err().inner(), Inner::BuildError(BuildError::InvalidFilePath(_))));
+        assert!(!outside.join("x").exists());
+    }
+
+    #[test]
+    fn placed_symlinks_are_not_followed() {
+        let dir = scratch("placed-symlink");
+        let outside = dir.join("outside");
+        fs::create_dir_all(&outside).unwrap();
+
+        let result = place(&dir, &[
+            file("/etc", FilesystemEntry::Symlink(outside.clone())),
+            file("/etc/passwd", FilesystemEntry::Text("root;0;0;root;/root;/bin/ion".into())),
+        ]);
+
+        assert!(matches!(result.unwrap_err().inner(), Inner::BuildError(BuildError::InvalidFilePath(_))));
+        assert!(!outside.join("passwd").exists());
+    }
+
+    #[test]
+    fn symlinks_are_replaced_rather_than_written_through() {
+        let dir = scratch("replace-symlink");
+        let outside = dir.join("outside");
+
+        place(&dir, &[
+            file("/motd", FilesystemEntry::Symlink(outside.clone())),
+            file("/motd", FilesystemEntry::Text("hello".into())),
+        ])
+        .unwrap();
+
+        assert!(!outside.exists());
+        assert_eq!(fs::read_to_string(dir.join("mount/motd")).unwrap(), "hello");
+    }
+
+    #[test]
+    fn mkdir_false_is_rejected() {
+        let dir = scratch("mkdir-false");
+        let result = place(&dir, &[file("/empty", FilesystemEntry::Mkdir(false))]);
+
+        assert!(matches!(result.unwrap_err().inner(), Inner::BuildError(BuildError::InvalidFilePath(_))));
+        assert!(!dir.join("mount/empty").exists());
+    }
+}
@@ -0,0 +1,345 @@
+use std::fs;
+use std::io::{BufRead, BufReader, ErrorKind};
+use std::ops::Deref;
+use std::os::unix::net::UnixListener;
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use fuser::BackgroundSession;
+use log::{debug, error, warn};
+use serde_json::{json, Value};
+
+use hub::config::{ImageConfig, ImageFormat};
+use hub::error::*;
+use hub::global::Inner;
+use hub::paths::PathManager;
+
+use crate::DiskManager;
+use crate::fuse::PartitionFS;
+use crate::qemu::qmp::Qmp;
+
+pub struct QCow2 {
+    backing: PathBuf,
+    image: Arc<ImageConfig>,
+    paths: Arc<PathManager>,
+    proc: Option<QemuProc>,
+    session: Option<BackgroundSession>,
+}
+
+pub mod qmp;
+
+const EXPORT_ID: &str = "exp0";
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long the daemon may take to connect to the monitor socket
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long the daemon may take to exit after being asked to quit
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub(crate) struct QemuProc {
+    qmp: Qmp,
+    qemu: Daemon,
+    socket_path: PathBuf,
+    pid_file: PathBuf,
+}
+
+/// The `qemu-storage-daemon` process. It is killed if dropped while still running.
+struct Daemon {
+    child: Child,
+    stderr: Option<JoinHandle<String>>,
+}
+
+impl Daemon {
+    fn spawn(mut command: Command) -> Result<Self> {
+        // The daemon gets its own process group so Ctrl-C in the terminal doesn't reach it. It's shut down in order instead
+        let mut child = match command.stdin(Stdio::null()).stderr(Stdio::piped()).process_group(0).spawn() {
+            Ok(child) => child,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                return Err(BuildError::HostToolMissing("qemu-storage-daemon".into()).into());
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        // The pipe is drained continuously so the daemon can't block on a full buffer
+        let stderr = child.stderr.take().map(|pipe| {
+            std::thread::spawn(move || {
+                let mut log = String::new();
+                for line in BufReader::new(pipe).lines().map_while(std::result::Result::ok) {
+                    debug!("qemu-storage-daemon: {}", &line);
+                    log.push_str(&line);
+                    log.push('\n');
+                }
+
+                log
+            })
+        });
+
+        Ok(Self { child, stderr })
+    }
+
+    /// Everything the daemon has written to stderr. Only call this once the daemon has exited.
+    fn stderr(&mut self) -> String {
+        self.stderr
+            .take()
+            .and_then(|reader| reader.join().ok())
+            .unwrap_or_default()
+    }
+
+    fn terminate(&mut self) {
+        if let Err(err) = self.child.kill().and_then(|_| self.child.wait()) {
+            error!("Failed to kill storage daemon: {}", err);
+        }
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        if let Ok(None) = self.child.try_wait() {
+            warn!("Storage daemon still running. Killing it");
+            self.terminate();
+        }
+    }
+}
+
+/// How the storage daemon exposes the raw contents of the image
+pub(crate) enum Export {
+    /// Mounts the raw disk over the image file through FUSE
+    Fuse,
+    /// Serves the raw disk over NBD on a unix socket under the given export name
+    Nbd(PathBuf, String),
+}
+
+/// Creates an empty image of the configured size and format
+pub(crate) fn create_image(config: &ImageConfig, path: &PathManager) -> Result<()> {
+    let create = Command::new("qemu-img")
+        .arg("create")
+        .arg(path.final_image())
+        .args([
+            format!("{}M", config.size).as_ref(),
+            "-f",
+            match config.format {
+                ImageFormat::Raw => "raw",
+                #[cfg(feature = "qemu")]
+                ImageFormat::QCow2 => "qcow2",
+            },
+        ])
+        .spawn()?
+        .wait()?;
+
+    match create.success() {
+        true => Ok(()),
+        false => Err(Error::from(BuildError::FailedToCreateImage)),
+    }
+}
+
+impl QemuProc {
+    pub fn init(paths: &PathManager, format: ImageFormat, export: Export) -> Result<Self> {
+        let backing = &paths.final_image();
+        let monitor = paths.monitor_socket();
+        let pid_file = paths.daemon_pid_file();
+
+        let file_driver = format!(
+            "node-name=prot-node,driver=file,filename={}",
+            backing.to_string_lossy()
+        );
+        let qcow_driver = format!(
+            "node-name=fmt-node,driver={},file=prot-node",
+            match format {
+                ImageFormat::Raw => "raw",
+                #[cfg(feature = "qemu")]
+                ImageFormat::QCow2 => "qcow2",
+            }
+        );
+        let qemu_monitor = format!(
+            "socket,path={},id=qemu-monitor",
+            monitor.to_string_lossy()
+        );
+        let export = match export {
+            Export::Fuse => vec![
+                "--export".to_owned(),
+                format!(
+                    "type=fuse,id={},node-name=fmt-node,mountpoint={},writable=on",
+                    EXPORT_ID,
+                    backing.to_string_lossy()
+                ),
+            ],
+            Export::Nbd(socket, name) => {
+                if socket.exists() {
+                    fs::remove_file(&socket)?;
+                }
+
+                vec![
+                    "--nbd-server".to_owned(),
+                    format!("addr.type=unix,addr.path={}", socket.to_string_lossy()),
+                    "--export".to_owned(),
+                    format!("type=nbd,id={},node-name=fmt-node,name={},writable=on", EXPORT_ID, name),
+                ]
+            }
+        };
+
+        if monitor.exists() {
+            fs::remove_file(&monitor)?;
+        }
+
+        let server = UnixListener::bind(monitor.clone())?;
+        server.set_nonblocking(true)?;
+
+        let mut command = Command::new("qemu-storage-daemon");
+        command
+            .args(["--blockdev", file_driver.as_ref()])
+            .args(["--blockdev", qcow_driver.as_ref()])
+            .args(["--chardev", qemu_monitor.as_ref()])
+            .args(["--monitor", "chardev=qemu-monitor"])
+            .arg("--pidfile")
+            .arg(&pid_file)
+            .args(export);
+
+        let mut qemu = Daemon::spawn(command)?;
+
+        let start = Instant::now();
+        let socket = loop {
+            match server.accept() {
+                Ok((socket, _)) => break socket,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                Err(err) => return Err(err.into()),
+            }
+
+            if let Some(status) = qemu.child.try_wait()? {
+                return Err(BuildError::StorageDaemonExited(status, qemu.stderr()).into());
+            }
+
+            if start.elapsed() > STARTUP_TIMEOUT {
+                qemu.terminate();
+                return Err(BuildError::StorageDaemonTimeout(qemu.stderr()).into());
+            }
+
+            std::thread::sleep(Duration::from_millis(20));
+        };
+
+        socket.set_nonblocking(false)?;
+        let qmp = match Qmp::handshake(socket) {
+            Ok(qmp) => qmp,
+            Err(err) => {
+                return Err(match qemu.child.try_wait()? {
+                    Some(status) => BuildError::StorageDaemonExited(status, qemu.stderr()).into(),
+                    None => err,
+                })
+            }
+        };
+
+        debug!("Feature Negotiation complete");
+
+        Ok(Self {
+            qmp,
+            qemu,
+            socket_path: monitor.clone(),
+            pid_file,
+        })
+    }
+
+    /// Removes the export, waiting until QEMU has released it, then asks the daemon to quit
+    pub fn kill(mut self) -> Result<ExitStatus> {
+        match self.qmp.execute("block-export-del", json!({ "id": EXPORT_ID })) {
+            // The daemon is asked to quit regardless, so it isn't left running if the export is slow to be released
+            Ok(_) => {
+                if let Err(err) = self.qmp.wait_event(qmp::BLOCK_EXPORT_DELETED, EXPORT_TIMEOUT) {
+                    warn!("Block export was not released: {:?}", err);
+                }
+            }
+            Err(err) => warn!("Failed to remove block export: {:?}", err),
+        }
+
+        match self.qmp.execute("quit", Value::Null).map_err(Error::into_inner) {
+            // QEMU may hang up before its reply is read
+            Ok(_) | Err(Inner::BuildError(BuildError::QmpConnectionClosed)) => {}
+            Err(err) => return Err(err.into()),
+        }
+
+        let start = Instant::now();
+        let status = loop {
+            if let Some(status) = self.qemu.child.try_wait()? {
+                break status;
+            }
+
+            if start.elapsed() > SHUTDOWN_TIMEOUT {
+                warn!("Storage daemon did not quit. Killing it");
+                self.qemu.terminate();
+                break self.qemu.child.wait()?;
+            }
+
+            std::thread::sleep(Duration::from_millis(20));
+        };
+
+        fs::remove_file(&self.socket_path)?;
+        // QEMU removes its pidfile on a clean exit, but not if it had to be killed
+        if self.pid_file.exists() {
+            fs::remove_file(&self.pid_file)?;
+        }
+
+        return Ok(status);
+    }
+}
+
+#[async_trait(? Send)]
+impl DiskManager for QCow2 {
+    fn backing(&self) -> PathBuf {
+        self.backing.clone()
+    }
+
+    fn image(&self) -> &ImageConfig {
+        self.image.deref()
+    }
+    fn paths(&self) -> &PathManager {
+        self.paths.deref()
+    }
+
+    fn create_disk(config: Arc<ImageConfig>, path: Arc<PathManager>) -> Result<Self> {
+        create_image(&config, &path)?;
+
+        Ok(Self {
+            backing: path.final_image(),
+            image: config,
+            paths: Arc::clone(&path),
+            proc: None,
+            session: None,
+        })
+    }
+
+    fn mount(&mut self) -> Result<()> {
+        let proc = QemuProc::init(&self.paths, self.image.format, Export::Fuse)?;
+        self.proc = Some(proc);
+        debug!("Qemu Storage Daemon running");
+
+        Ok(())
+    }
+
+    fn expose_partitions(&mut self) -> Result<()> {
+        self.session = Some(PartitionFS::mount(&self.paths)?);
+        Ok(())
+    }
+
+    fn unmount(&mut self) -> Result<()> {
+        // PartitionFS holds the exported image open, so it has to go before the export does
+        if let Some(session) = self.session.take() {
+            debug!("Unmounting PartitionFS");
+            session.join();
+        }
+
+        debug!("Killing Storage Daemon");
+        if let Some(proc) = self.proc.take() {
+            proc.kill()?;
+        };
+
+        Ok(())
+    }
+}
+// 
+// impl Drop for QCow2 {
+//     fn drop(&mut self) {
+//         self.unmount().expect("Failed to gracefully unmount")
+//     }
+// }
\ No newline at end of file
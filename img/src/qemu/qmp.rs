@@ -0,0 +1,154 @@
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::net::UnixStream;
+use std::time::{Duration, Instant};
+
+use log::{debug, trace};
+use serde_json::{json, Value};
+
+use hub::error::*;
+
+/// Emitted once a block export has been shut down and all of its resources released
+pub const BLOCK_EXPORT_DELETED: &str = "BLOCK_EXPORT_DELETED";
+
+/// How long to wait for a reply to a command before giving up
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// An asynchronous notification sent by QEMU
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub name: String,
+    pub data: Value,
+}
+
+/// A client for the QEMU Machine Protocol.
+/// Messages are newline-delimited JSON objects. Each command is tagged with an `id` which QEMU echoes in its reply,
+/// so replies can be told apart from events arriving in between. Events are queued until they are asked for.
+pub struct Qmp {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+    next_id: u64,
+    events: VecDeque<Event>,
+}
+
+impl Qmp {
+    /// Reads QEMU's greeting and negotiates capabilities, leaving the connection in command mode
+    pub fn handshake(socket: UnixStream) -> Result<Self> {
+        let mut qmp = Self {
+            reader: BufReader::new(socket.try_clone()?),
+            writer: socket,
+            next_id: 0,
+            events: VecDeque::new(),
+        };
+
+        match qmp.read_message(COMMAND_TIMEOUT)? {
+            greeting if greeting.get("QMP").is_some() => {
+                debug!("QMP greeting: {}", greeting["QMP"]["version"]);
+            }
+            other => return Err(BuildError::QmpUnexpectedMessage(other).into()),
+        }
+
+        qmp.execute("qmp_capabilities", json!({ "enable": [] }))?;
+
+        Ok(qmp)
+    }
+
+    /// Runs a command and waits for its reply. A `{"error": ...}` reply is returned as `BuildError::QmpError`
+    pub fn execute(&mut self, command: &str, arguments: Value) -> Result<Value> {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let message = match arguments {
+            Value::Null => json!({ "execute": command, "id": id }),
+            arguments => json!({ "execute": command, "arguments": arguments, "id": id }),
+        };
+
+        trace!("QMP -> {}", &message);
+        self.writer.write_all(format!("{}\n", message).as_bytes())?;
+
+        let deadline = Instant::now() + COMMAND_TIMEOUT;
+        loop {
+            let message = self.read_message(deadline.saturating_duration_since(Instant::now()))?;
+
+            if message.get("id").and_then(Value::as_u64) != Some(id) {
+                match message.get("event") {
+                    Some(_) => self.queue_event(message),
+                    None => return Err(BuildError::QmpUnexpectedMessage(message).into()),
+                }
+
+                continue;
+            }
+
+            if let Some(value) = message.get("return") {
+                return Ok(value.clone());
+            }
+
+            if let Some(error) = message.get("error") {
+                return Err(BuildError::QmpError(
+                    error["class"].as_str().unwrap_or("GenericError").to_owned(),
+                    error["desc"].as_str().unwrap_or_default().to_owned(),
+                )
+                .into());
+            }
+
+            return Err(BuildError::QmpUnexpectedMessage(message).into());
+        }
+    }
+
+    /// Waits for a particular event, returning any matching event which has already arrived first.
+    /// Other events received in the meantime remain queued.
+    pub fn wait_event(&mut self, name: &str, timeout: Duration) -> Result<Event> {
+        if let Some(index) = self.events.iter().position(|event| event.name == name) {
+            return Ok(self.events.remove(index).expect("Index is in bounds"));
+        }
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let message = self.read_message(deadline.saturating_duration_since(Instant::now()))?;
+
+            match message.get("event").and_then(Value::as_str) {
+                Some(event) if event == name => return Ok(Self::to_event(message)),
+                Some(_) => self.queue_event(message),
+                None => return Err(BuildError::QmpUnexpectedMessage(message).into()),
+            }
+        }
+    }
+
+    fn to_event(message: Value) -> Event {
+        Event {
+            name: message["event"].as_str().unwrap_or_default().to_owned(),
+            data: message.get("data").cloned().unwrap_or(Value::Null),
+        }
+    }
+
+    fn queue_event(&mut self, message: Value) {
+        let event = Self::to_event(message);
+        debug!("QMP event: {} {}", &event.name, &event.data);
+        self.events.push_back(event);
+    }
+
+    fn read_message(&mut self, timeout: Duration) -> Result<Value> {
+        if timeout.is_zero() {
+            return Err(BuildError::QmpTimeout.into());
+        }
+
+        self.reader.get_ref().set_read_timeout(Some(timeout))?;
+
+        let mut line = String::new();
+        loop {
+            match self.reader.read_line(&mut line) {
+                Ok(0) => return Err(BuildError::QmpConnectionClosed.into()),
+                Ok(_) if line.trim().is_empty() => line.clear(),
+                Ok(_) => break,
+                Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    return Err(BuildError::QmpTimeout.into());
+                }
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        trace!("QMP <- {}", line.trim_end());
+        Ok(serde_json::from_str(&line)?)
+    }
+}
@@ -3,16 +3,21 @@ use std::ops::Deref;
 use std::path::PathBuf;
 use std::sync::Arc;
 
-use hub::config::ImageConfig;
+use fuser::BackgroundSession;
+use log::debug;
+
+use hub::config::{ImageConfig, ImageFormat};
 use hub::error::*;
 use hub::paths::PathManager;
 
 use crate::DiskManager;
+use crate::fuse::PartitionFS;
 
 pub struct Raw {
     backing: PathBuf,
     img: Arc<ImageConfig>,
     paths: Arc<PathManager>,
+    session: Option<BackgroundSession>,
 }
 
 impl DiskManager for Raw {
@@ -30,6 +35,10 @@ impl DiskManager for Raw {
 
 
     fn create_disk(config: Arc<ImageConfig>, paths: Arc<PathManager>) -> Result<Self> {
+        if !matches!(config.format, ImageFormat::Raw) {
+            return Err(BuildError::InvalidDiskType.into());
+        }
+
         OpenOptions::new()
             .read(true)
             .write(true)
@@ -41,15 +50,27 @@ impl DiskManager for Raw {
             backing: paths.final_image().to_owned(),
             img: Arc::clone(&config),
             paths,
+            session: None,
         })
     }
 
+    // The image is already a plain file, so PartitionFS can read it directly
     fn mount(&mut self) -> Result<()> {
-        todo!()
+        Ok(())
+    }
+
+    fn expose_partitions(&mut self) -> Result<()> {
+        self.session = Some(PartitionFS::mount(&self.paths)?);
+        Ok(())
     }
 
     fn unmount(&mut self) -> Result<()> {
-        todo!()
+        if let Some(session) = self.session.take() {
+            debug!("Unmounting PartitionFS");
+            session.join();
+        }
+
+        Ok(())
     }
 }
 
@@ -0,0 +1,128 @@
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::fd::AsRawFd;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use log::{debug, error, info, warn};
+
+use hub::error::*;
+use hub::paths::PathManager;
+
+use crate::loopdev::LoopDevice;
+use crate::mnt;
+
+/// How long a stale storage daemon may take to exit before it is killed
+const DAEMON_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Claims a build directory for the lifetime of a build.
+/// The lock is tied to the open file, so it is released by the kernel even if the build crashes.
+pub struct BuildLock {
+    _file: File,
+}
+
+impl BuildLock {
+    /// Fails with `BuildError::BuildDirLocked` if another build is using the directory
+    pub fn acquire(paths: &PathManager) -> Result<Self> {
+        fs::create_dir_all(paths.build_dir())?;
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(paths.lock_file())?;
+
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let err = std::io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::EWOULDBLOCK) {
+                return Err(err.into());
+            }
+
+            let mut owner = String::new();
+            file.read_to_string(&mut owner)?;
+            return Err(BuildError::BuildDirLocked(paths.build_dir(), owner.trim().to_owned()).into());
+        }
+
+        file.set_len(0)?;
+        write!(file, "{}", std::process::id())?;
+
+        debug!("Acquired lock on {:?}", paths.build_dir());
+
+        Ok(Self { _file: file })
+    }
+}
+
+/// Cleans up after a build which didn't shut down cleanly.
+/// Filesystems mounted under the build directory are unmounted, its storage daemon is stopped, stale sockets are removed
+/// and loop devices backed by the image are detached. Holding the lock guarantees none of these belong to a running build.
+pub fn recover(paths: &PathManager, _lock: &BuildLock) -> Result<()> {
+    if !paths.build_dir().exists() {
+        return Ok(());
+    }
+
+    // Most recently mounted first, so filesystems go before the partitions they live on
+    for (target, fs_type) in mnt::mounts_under(&paths.build_dir()) {
+        warn!("Unmounting stale {} mount at {:?}", fs_type, &target);
+
+        if let Err(err) = mnt::unmount_stale(&target, &fs_type) {
+            error!("Failed to unmount {:?}: {:?}", &target, err);
+            return Err(BuildError::StaleMount(target).into());
+        }
+    }
+
+    stop_daemon(&paths.daemon_pid_file())?;
+
+    for socket in [paths.monitor_socket(), paths.nbd_socket()] {
+        if socket.exists() {
+            debug!("Removing stale socket {:?}", &socket);
+            fs::remove_file(&socket)?;
+        }
+    }
+
+    let image = paths.final_image();
+    if image.exists() && LoopDevice::available() {
+        let detached = LoopDevice::detach_stale(&image)?;
+        if detached > 0 {
+            info!("Detached {} stale loop device(s)", detached);
+        }
+    }
+
+    Ok(())
+}
+
+/// Stops the storage daemon recorded in the PID file, if it's still running
+fn stop_daemon(pid_file: &Path) -> Result<()> {
+    let Ok(pid) = fs::read_to_string(pid_file) else {
+        return Ok(());
+    };
+
+    if let Ok(pid) = pid.trim().parse::<libc::pid_t>() {
+        let proc = Path::new("/proc").join(pid.to_string());
+
+        // The PID may have been reused since the daemon exited
+        let is_daemon = fs::read(proc.join("cmdline"))
+            .map(|cmdline| String::from_utf8_lossy(&cmdline).contains("qemu-storage-daemon"))
+            .unwrap_or(false);
+
+        if is_daemon {
+            warn!("Stopping stale storage daemon ({})", pid);
+            unsafe { libc::kill(pid, libc::SIGTERM) };
+
+            let start = Instant::now();
+            while proc.exists() {
+                if start.elapsed() > DAEMON_TIMEOUT {
+                    warn!("Storage daemon did not quit. Killing it");
+                    unsafe { libc::kill(pid, libc::SIGKILL) };
+                    break;
+                }
+
+                std::thread::sleep(Duration::from_millis(50));
+            }
+        }
+    }
+
+    fs::remove_file(pid_file)?;
+
+    Ok(())
+}
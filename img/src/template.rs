@@ -0,0 +1,116 @@
+use std::fs::File;
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+
+use hub::config::ConfigFile;
+use hub::error::*;
+use hub::paths::PathManager;
+
+/// Fills in the `{{ ... }}` placeholders of a template. Each placeholder names a value, optionally followed by the
+/// argument which selects it:
+///
+/// * `{{ name }}`, `{{ description }}`: the config's name and description
+/// * `{{ label }}`: the image's label
+/// * `{{ var <name> }}`: an entry of `::[variables]`
+/// * `{{ version <component> }}`: a component's version
+/// * `{{ sha256 <artifact> }}`: the hex-encoded SHA-256 hash of an artifact
+pub(crate) fn render(template: &str, config: &ConfigFile, paths: &PathManager) -> Result<String> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        rendered.push_str(&rest[..start]);
+
+        let Some(end) = rest[start..].find("}}") else {
+            return Err(BuildError::InvalidTemplate(rest[start..].to_owned()).into());
+        };
+
+        let placeholder = &rest[start + 2..start + end];
+        rendered.push_str(&value(placeholder, config, paths)?);
+        rest = &rest[start + end + 2..];
+    }
+
+    rendered.push_str(rest);
+
+    Ok(rendered)
+}
+
+/// The hex-encoded SHA-256 hash of a file
+pub(crate) fn sha256(path: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut File::open(path)?, &mut hasher)?;
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn value(placeholder: &str, config: &ConfigFile, paths: &PathManager) -> Result<String> {
+    let invalid = || Error::from(BuildError::InvalidTemplate(placeholder.trim().to_owned()));
+
+    match placeholder.split_whitespace().collect::<Vec<_>>()[..] {
+        ["name"] => Ok(config.name.clone()),
+        ["description"] => Ok(config.description.clone().unwrap_or_default()),
+        ["label"] => Ok(config.image.label.clone()),
+        ["var", name] => config.variables.get(name).cloned().ok_or_else(invalid),
+        ["version", component] => config
+            .components
+            .iter()
+            .find(|candidate| candidate.name == component)
+            .and_then(|component| component.version.clone())
+            .ok_or_else(invalid),
+        ["sha256", artifact] => {
+            let source = paths
+                .artifact(artifact)
+                .filter(|path| path.is_file())
+                .ok_or(BuildError::MissingArtifact(artifact.to_owned()))?;
+
+            sha256(&source)
+        }
+        _ => Err(invalid()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use hub::global::Inner;
+
+    use super::*;
+
+    fn render(template: &str) -> Result<String> {
+        let config: ConfigFile = serde_json::from_str(r#"{
+            "name": "test",
+            "image": { "label": "disk", "size": 1 },
+            "variables": { "arch": "x86_64" }
+        }"#).unwrap();
+        let paths = PathManager::new(Arc::new(config.clone()), Some(std::env::temp_dir().join("redox-builder-template")));
+
+        super::render(template, &config, &paths)
+    }
+
+    fn invalid(result: Result<String>) -> String {
+        match result.unwrap_err().inner() {
+            Inner::BuildError(BuildError::InvalidTemplate(placeholder)) => placeholder.clone(),
+            err => panic!("Unexpected error: {:?}", err),
+        }
+    }
+
+    #[test]
+    fn placeholders_are_filled_in() {
+        assert_eq!(render("{{ name }} on {{label}} for {{ var arch }}").unwrap(), "test on disk for x86_64");
+        assert_eq!(render("no placeholders }}").unwrap(), "no placeholders }}");
+    }
+
+    #[test]
+    fn unterminated_placeholders_are_rejected() {
+        assert_eq!(invalid(render("{{ name }} {{ label")), "{{ label");
+    }
+
+    #[test]
+    fn unknown_placeholders_are_rejected() {
+        assert_eq!(invalid(render("{{ colour }}")), "colour");
+        assert_eq!(invalid(render("{{ var missing }}")), "var missing");
+        assert_eq!(invalid(render("{{ name extra }}")), "name extra");
+    }
+}
@@ -0,0 +1,420 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use log::warn;
+
+use hub::error::*;
+
+use crate::filesystem::components;
+
+/// A directory tree or archive flattened into its entries, relative to its root
+pub(crate) struct Tree {
+    pub entries: Vec<TreeEntry>,
+    /// Where an archive's files were unpacked to. Removed along with the tree
+    stage: Option<Stage>,
+}
+
+/// A directory which an archive is unpacked into, removed again once it's dropped
+struct Stage(PathBuf);
+
+impl Stage {
+    /// Creates an empty directory below `dir`, which isn't shared with any other archive being unpacked at the time
+    fn new(dir: &Path) -> Result<Self> {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+        let stage = dir.join(format!("{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed)));
+        if stage.exists() {
+            fs::remove_dir_all(&stage)?;
+        }
+        fs::create_dir_all(&stage)?;
+
+        Ok(Self(stage))
+    }
+
+    /// Unpacks the contents of an entry into a file of its own, so they needn't be held in memory
+    fn unpack(&self, index: usize, mut reader: impl Read) -> Result<PathBuf> {
+        let path = self.0.join(index.to_string());
+        std::io::copy(&mut reader, &mut File::create(&path)?)?;
+
+        Ok(path)
+    }
+}
+
+impl Drop for Stage {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_dir_all(&self.0) {
+            warn!("Failed to remove {:?}: {:?}", &self.0, err);
+        }
+    }
+}
+
+pub(crate) struct TreeEntry {
+    pub path: PathBuf,
+    /// The entry's permission bits, if the source records them
+    pub mode: Option<u32>,
+    pub kind: EntryKind,
+}
+
+pub(crate) enum EntryKind {
+    Dir,
+    /// A file on the host or unpacked from an archive, read only once it's placed
+    HostFile(PathBuf),
+    Symlink(PathBuf),
+}
+
+/// Decides which entries of a tree are placed. Paths are matched relative to the root of the tree, where `*` doesn't
+/// cross directory boundaries but `**` does.
+pub(crate) struct Filter {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+}
+
+impl Filter {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self> {
+        Ok(Self {
+            include: match include.is_empty() {
+                true => None,
+                false => Some(glob_set(include)?),
+            },
+            exclude: glob_set(exclude)?,
+        })
+    }
+
+    /// Whether the entry should be placed. When only some entries are included, directories aren't placed in their own
+    /// right, but are still created to hold the entries inside them.
+    pub fn accepts(&self, entry: &TreeEntry) -> bool {
+        if entry.path.ancestors().any(|path| !path.as_os_str().is_empty() && self.exclude.is_match(path)) {
+            return false;
+        }
+
+        match (&self.include, &entry.kind) {
+            (None, _) => true,
+            (Some(_), EntryKind::Dir) => false,
+            (Some(include), _) => include.is_match(&entry.path),
+        }
+    }
+
+    fn excludes(&self, path: &Path) -> bool {
+        self.exclude.is_match(path)
+    }
+}
+
+fn glob_set(globs: &[String]) -> Result<GlobSet> {
+    let mut set = GlobSetBuilder::new();
+
+    for glob in globs {
+        let invalid = |err: globset::Error| Error::from(BuildError::InvalidGlob(glob.clone(), err.to_string()));
+        set.add(GlobBuilder::new(glob).literal_separator(true).build().map_err(invalid)?);
+    }
+
+    set.build()
+        .map_err(|err| BuildError::InvalidGlob(globs.join(", "), err.to_string()).into())
+}
+
+impl Tree {
+    /// Walks a directory on the host. Entries are sorted by path, so the order they're placed in doesn't depend on the
+    /// host's filesystem. Excluded directories aren't descended into.
+    pub fn walk(root: &Path, filter: &Filter) -> Result<Self> {
+        let mut tree = Self { entries: vec![], stage: None };
+        tree.walk_dir(root, Path::new(""), filter)?;
+
+        Ok(tree)
+    }
+
+    fn walk_dir(&mut self, root: &Path, dir: &Path, filter: &Filter) -> Result<()> {
+        let mut children = fs::read_dir(root.join(dir))?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        children.sort();
+
+        for name in children {
+            let path = dir.join(name);
+            let source = root.join(&path);
+
+            if filter.excludes(&path) {
+                continue;
+            }
+
+            let metadata = fs::symlink_metadata(&source)?;
+            let mode = Some(metadata.permissions().mode() & 0o7777);
+
+            if metadata.is_symlink() {
+                let target = fs::read_link(&source)?;
+                self.entries.push(TreeEntry { path, mode: None, kind: EntryKind::Symlink(target) });
+            } else if metadata.is_dir() {
+                self.entries.push(TreeEntry { path: path.clone(), mode, kind: EntryKind::Dir });
+                self.walk_dir(root, &path, filter)?;
+            } else if metadata.is_file() {
+                self.entries.push(TreeEntry { path, mode, kind: EntryKind::HostFile(source) });
+            } else {
+                warn!("Skipping {:?}. Only files, directories and symlinks can be placed", &source);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads a `.tar`, `.tar.zst`/`.tzst` or `.zip` archive, unpacking its files below `stage`. Entries are sorted by
+    /// path, so the order they're placed in doesn't depend on how the archive was made.
+    pub fn extract(archive: &Path, stage: &Path) -> Result<Self> {
+        let name = archive.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+        if ![".tar", ".tar.zst", ".tzst", ".zip"].iter().any(|extension| name.ends_with(extension)) {
+            return Err(BuildError::UnsupportedArchive(archive.to_owned()).into());
+        }
+
+        let stage = Stage::new(stage)?;
+
+        let mut entries = if name.ends_with(".tar") {
+            read_tar(File::open(archive)?, &stage)?
+        } else if name.ends_with(".zip") {
+            read_zip(File::open(archive)?, &stage)?
+        } else {
+            read_tar(zstd::stream::read::Decoder::new(File::open(archive)?)?, &stage)?
+        };
+
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+
+        // Later entries replace earlier ones of the same name, as they would when extracting the archive
+        entries.reverse();
+        let mut seen = HashSet::new();
+        entries.retain(|entry| seen.insert(entry.path.clone()));
+        entries.reverse();
+
+        Ok(Self { entries, stage: Some(stage) })
+    }
+}
+
+/// Strips leading `/` and `./` from paths stored in archives, and refuses any which climb out of the archive
+fn relative(path: &Path) -> Result<PathBuf> {
+    Ok(components(path)?.into_iter().collect())
+}
+
+fn read_tar(reader: impl Read, stage: &Stage) -> Result<Vec<TreeEntry>> {
+    let mut archive = tar::Archive::new(reader);
+    let mut entries = vec![];
+    // Hard links refer to a file earlier in the archive, which has already been unpacked by then
+    let mut unpacked = HashMap::<PathBuf, PathBuf>::new();
+
+    for (index, entry) in archive.entries()?.enumerate() {
+        let entry = entry?;
+        let path = relative(&entry.path()?)?;
+        let mode = entry.header().mode().ok().map(|mode| mode & 0o7777);
+
+        if path.as_os_str().is_empty() {
+            continue;
+        }
+
+        let kind = match entry.header().entry_type() {
+            tar::EntryType::Directory => EntryKind::Dir,
+            tar::EntryType::Regular | tar::EntryType::Continuous => {
+                let source = stage.unpack(index, entry)?;
+
+                unpacked.insert(path.clone(), source.clone());
+                EntryKind::HostFile(source)
+            }
+            tar::EntryType::Symlink => match entry.link_name()? {
+                Some(target) => EntryKind::Symlink(target.into_owned()),
+                None => continue,
+            },
+            tar::EntryType::Link => {
+                let target = entry.link_name()?.map(|target| relative(&target)).transpose()?;
+
+                match target.and_then(|target| unpacked.get(&target)) {
+                    Some(source) => EntryKind::HostFile(source.clone()),
+                    None => {
+                        warn!("Skipping hard link {:?}. Its target isn't in the archive", &path);
+                        continue;
+                    }
+                }
+            }
+            other => {
+                warn!("Skipping {:?}. Entries of type {:?} can't be placed", &path, other);
+                continue;
+            }
+        };
+
+        entries.push(TreeEntry { path, mode, kind });
+    }
+
+    Ok(entries)
+}
+
+fn read_zip(reader: File, stage: &Stage) -> Result<Vec<TreeEntry>> {
+    let mut archive = zip::ZipArchive::new(reader).map_err(std::io::Error::from)?;
+    let mut entries = vec![];
+
+    for index in 0..archive.len() {
+        let mut file = archive.by_index(index).map_err(std::io::Error::from)?;
+        let path = file
+            .enclosed_name()
+            .map(relative)
+            .ok_or(BuildError::InvalidFilePath(PathBuf::from(file.name())))??;
+
+        if path.as_os_str().is_empty() {
+            continue;
+        }
+
+        let mode = file.unix_mode().map(|mode| mode & 0o7777);
+
+        let kind = if file.is_dir() {
+            EntryKind::Dir
+        } else {
+            // Zip has no symlinks of its own. Unix tools store the target as the contents and mark the mode instead
+            match file.unix_mode().map(|mode| mode & libc::S_IFMT) {
+                Some(libc::S_IFLNK) => {
+                    let mut target = String::new();
+                    file.read_to_string(&mut target)?;
+                    EntryKind::Symlink(PathBuf::from(target))
+                }
+                _ => EntryKind::HostFile(stage.unpack(index, file)?),
+            }
+        };
+
+        entries.push(TreeEntry { path, mode, kind });
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use hub::global::Inner;
+
+    use super::*;
+
+    fn entry(path: &str, kind: EntryKind) -> TreeEntry {
+        TreeEntry { path: PathBuf::from(path), mode: None, kind }
+    }
+
+    fn file(path: &str) -> TreeEntry {
+        entry(path, EntryKind::HostFile(PathBuf::from(path)))
+    }
+
+    fn filter(include: &[&str], exclude: &[&str]) -> Filter {
+        let strings = |globs: &[&str]| globs.iter().map(|glob| glob.to_string()).collect::<Vec<_>>();
+        Filter::new(&strings(include), &strings(exclude)).unwrap()
+    }
+
+    #[test]
+    fn single_stars_stay_within_a_directory() {
+        let filter = filter(&["bin/*"], &[]);
+
+        assert!(filter.accepts(&file("bin/ion")));
+        assert!(!filter.accepts(&file("bin/extra/ion")));
+        assert!(!filter.accepts(&file("lib/libc.so")));
+    }
+
+    #[test]
+    fn double_stars_cross_directories() {
+        let filter = filter(&["**/*.so"], &[]);
+
+        assert!(filter.accepts(&file("libc.so")));
+        assert!(filter.accepts(&file("lib/x86_64/libc.so")));
+        assert!(!filter.accepts(&file("lib/libc.a")));
+    }
+
+    #[test]
+    fn directories_are_only_placed_without_includes() {
+        assert!(filter(&[], &[]).accepts(&entry("bin", EntryKind::Dir)));
+        assert!(!filter(&["bin"], &[]).accepts(&entry("bin", EntryKind::Dir)));
+    }
+
+    #[test]
+    fn excludes_apply_to_everything_below_them() {
+        let filter = filter(&["**"], &["share/doc", "*.md"]);
+
+        assert!(!filter.accepts(&file("share/doc/ion/manual.html")));
+        assert!(!filter.accepts(&file("README.md")));
+        assert!(filter.accepts(&file("share/ion/README.md")));
+        assert!(filter.accepts(&file("share/docs")));
+    }
+
+    #[test]
+    fn invalid_globs_are_rejected() {
+        let result = Filter::new(&["[".into()], &[]);
+
+        assert!(matches!(result.err().unwrap().inner(), Inner::BuildError(BuildError::InvalidGlob(..))));
+    }
+
+    #[test]
+    fn archive_paths_are_made_relative() {
+        assert_eq!(relative(Path::new("/usr/bin")).unwrap(), PathBuf::from("usr/bin"));
+        assert_eq!(relative(Path::new("./usr/./bin")).unwrap(), PathBuf::from("usr/bin"));
+        assert_eq!(relative(Path::new(".")).unwrap(), PathBuf::new());
+    }
+
+    #[test]
+    fn archive_paths_climbing_out_are_rejected() {
+        for path in ["../etc/passwd", "usr/../../etc", "/.."] {
+            let result = relative(Path::new(path));
+            assert!(matches!(result.unwrap_err().inner(), Inner::BuildError(BuildError::InvalidFilePath(_))), "{}", path);
+        }
+    }
+
+    /// A directory for a test to unpack archives into
+    fn scratch(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("redox-builder-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn hard_links_refer_to_their_unpacked_target() {
+        let mut builder = tar::Builder::new(vec![]);
+
+        let mut data = tar::Header::new_gnu();
+        data.set_size(5);
+        data.set_mode(0o755);
+        builder.append_data(&mut data, "bin/ion", &b"shell"[..]).unwrap();
+
+        for (path, target) in [("bin/sh", "./bin/ion"), ("bin/dangling", "bin/missing")] {
+            let mut link = tar::Header::new_gnu();
+            link.set_entry_type(tar::EntryType::Link);
+            link.set_size(0);
+            link.set_mode(0o755);
+            builder.append_link(&mut link, path, target).unwrap();
+        }
+
+        let stage = Stage::new(&scratch("hard-links")).unwrap();
+        let entries = read_tar(&builder.into_inner().unwrap()[..], &stage).unwrap();
+        let paths = entries.iter().map(|entry| entry.path.to_str().unwrap()).collect::<Vec<_>>();
+
+        assert_eq!(paths, ["bin/ion", "bin/sh"]);
+        let (EntryKind::HostFile(file), EntryKind::HostFile(link)) = (&entries[0].kind, &entries[1].kind) else {
+            panic!("Files weren't unpacked");
+        };
+        assert_eq!(file, link);
+        assert_eq!(fs::read(link).unwrap(), b"shell");
+        assert_eq!(entries[1].mode, Some(0o755));
+    }
+
+    #[test]
+    fn archives_are_unpacked_until_the_tree_is_dropped() {
+        let dir = scratch("unpack");
+        let archive = dir.join("sysroot.tar");
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut builder = tar::Builder::new(File::create(&archive).unwrap());
+        for (path, contents) in [("usr/lib/libc.so", &b"old"[..]), ("usr/lib/libc.so", &b"new"[..])] {
+            let mut data = tar::Header::new_gnu();
+            data.set_size(contents.len() as u64);
+            data.set_mode(0o644);
+            builder.append_data(&mut data, path, contents).unwrap();
+        }
+        builder.into_inner().unwrap();
+
+        let tree = Tree::extract(&archive, &dir.join("stage")).unwrap();
+        let [TreeEntry { kind: EntryKind::HostFile(source), .. }] = &tree.entries[..] else {
+            panic!("Expected a single file");
+        };
+        assert_eq!(fs::read(source).unwrap(), b"new");
+
+        let source = source.clone();
+        drop(tree);
+        assert!(!source.exists());
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::HashSet;
+use std::path::Path;
+
+use log::{info, warn};
+
+use hub::config::ConfigFile;
+use hub::error::*;
+
+use crate::filesystem::Metadata;
+use crate::mnt::MountHandle;
+use crate::place::default_mtime;
+
+/// Writes `/etc/passwd`, `/etc/group` and `/etc/shadow` for the configured users and groups into the root partition,
+/// and creates each user's home directory. Fields are separated by `;`, as Redox expects.
+/// This happens before any `[[file]]` is placed, so files can be owned by these users by name.
+pub fn place_users(config: &ConfigFile, mounts: &MountHandle) -> Result<()> {
+    if config.users.is_empty() && config.groups.is_empty() {
+        return Ok(());
+    }
+
+    let root = config.image.root_partition().ok_or(BuildError::NoRootPartition)?;
+    let mount = mounts
+        .mount(&root.label)
+        .ok_or(BuildError::NoPartitionMountPoint(root.label.clone()))?;
+
+    info!("Placing {} user(s) and {} group(s) into '{}'", config.users.len(), config.groups.len(), &root.label);
+
+    let (passwd, group, shadow) = tables(config)?;
+    let mtime = Some(default_mtime(&config.image));
+
+    for (path, contents, mode) in [("/etc/passwd", passwd, 0o644), ("/etc/group", group, 0o644), ("/etc/shadow", shadow, 0o600)] {
+        let path = Path::new(path);
+        mount.driver.write_file(mount, path, contents.as_bytes())?;
+        mount.driver.set_metadata(mount, path, &Metadata { mode: Some(mode), uid: Some(0), gid: Some(0), mtime })?;
+    }
+
+    for user in config.users.iter() {
+        let home = user.home();
+        mount.driver.create_dir(mount, &home)?;
+        mount.driver.set_metadata(mount, &home, &Metadata {
+            mode: Some(0o700),
+            uid: Some(user.uid),
+            gid: Some(user.gid()),
+            mtime,
+        })?;
+    }
+
+    Ok(())
+}
+
+/// The contents of `/etc/passwd`, `/etc/group` and `/etc/shadow`, in that order
+fn tables(config: &ConfigFile) -> Result<(String, String, String)> {
+    let mut names = HashSet::new();
+    for name in config.users.iter().map(|user| &user.name) {
+        if !names.insert(name) {
+            return Err(BuildError::DuplicateOwner(name.clone()).into());
+        }
+    }
+
+    let mut passwd = String::new();
+    let mut shadow = String::new();
+
+    for user in config.users.iter() {
+        let password = match (user.password.as_ref(), user.locked) {
+            (Some(_), true) => return Err(BuildError::ConflictingPassword(user.name.clone()).into()),
+            (Some(hash), false) => hash.as_str(),
+            (None, true) => "!",
+            (None, false) => "",
+        };
+
+        if !config.groups.iter().any(|group| group.gid == user.gid()) {
+            warn!("The primary group of '{}' ({}) isn't defined", &user.name, user.gid());
+        }
+
+        passwd.push_str(&format!(
+            "{};{};{};{};{};{}\n",
+            &user.name,
+            user.uid,
+            user.gid(),
+            user.full_name.as_ref().unwrap_or(&user.name),
+            user.home().display(),
+            user.shell.display(),
+        ));
+        shadow.push_str(&format!("{};{}\n", &user.name, password));
+    }
+
+    let mut group_names = HashSet::new();
+    let mut group = String::new();
+
+    for entry in config.groups.iter() {
+        if !group_names.insert(&entry.name) {
+            return Err(BuildError::DuplicateOwner(entry.name.clone()).into());
+        }
+
+        if let Some(member) = entry.members.iter().find(|member| !names.contains(member)) {
+            return Err(BuildError::UnknownOwner(member.clone()).into());
+        }
+
+        // Redox lists the users whose primary group it is as members as well
+        let mut members = config
+            .users
+            .iter()
+            .filter(|user| user.gid() == entry.gid)
+            .map(|user| user.name.as_str())
+            .collect::<Vec<_>>();
+        for member in entry.members.iter() {
+            if !members.contains(&member.as_str()) {
+                members.push(member);
+            }
+        }
+
+        group.push_str(&format!("{};x;{};{}\n", &entry.name, entry.gid, members.join(",")));
+    }
+
+    Ok((passwd, group, shadow))
+}
+
+#[cfg(test)]
+mod tests {
+    use hub::global::Inner;
+
+    use super::*;
+
+    fn config(owners: &str) -> ConfigFile {
+        let config = format!(r#"{{ "name": "test", "image": {{ "label": "test", "size": 1 }}, {} }}"#, owners);
+        serde_json::from_str(&config).unwrap()
+    }
+
+    fn rejected(owners: &str) -> BuildError {
+        match tables(&config(owners)).unwrap_err().into_inner() {
+            Inner::BuildError(err) => err,
+            err => panic!("Unexpected error: {:?}", err),
+        }
+    }
+
+    #[test]
+    fn tables_are_written_in_redox_format() {
+        let (passwd, group, shadow) = tables(&config(r#"
+            "user": [
+                { "name": "root", "uid": 0 },
+                { "name": "user", "uid": 1000, "full_name": "A User", "shell": "/bin/sh", "password": "$6$hash" },
+                { "name": "daemon", "uid": 2, "gid": 0, "home": "/var/daemon", "locked": true }
+            ],
+            "group": [
+                { "name": "root", "gid": 0 },
+                { "name": "user", "gid": 1000, "members": ["root"] }
+            ]
+        "#)).unwrap();
+
+        assert_eq!(passwd, "root;0;0;root;/root;/bin/ion\nuser;1000;1000;A User;/home/user;/bin/sh\ndaemon;2;0;daemon;/var/daemon;/bin/ion\n");
+        assert_eq!(group, "root;x;0;root,daemon\nuser;x;1000;user,root\n");
+        assert_eq!(shadow, "root;\nuser;$6$hash\ndaemon;!\n");
+    }
+
+    #[test]
+    fn invalid_owners_are_rejected() {
+        assert!(matches!(
+            rejected(r#""user": [{ "name": "user", "uid": 1 }, { "name": "user", "uid": 2 }]"#),
+            BuildError::DuplicateOwner(name) if name == "user"
+        ));
+        assert!(matches!(
+            rejected(r#""group": [{ "name": "wheel", "gid": 1 }, { "name": "wheel", "gid": 2 }]"#),
+            BuildError::DuplicateOwner(name) if name == "wheel"
+        ));
+        assert!(matches!(
+            rejected(r#""group": [{ "name": "wheel", "gid": 1, "members": ["nobody"] }]"#),
+            BuildError::UnknownOwner(name) if name == "nobody"
+        ));
+        assert!(matches!(
+            rejected(r#""user": [{ "name": "user", "uid": 1, "password": "$6$hash", "locked": true }]"#),
+            BuildError::ConflictingPassword(name) if name == "user"
+        ));
+    }
+}
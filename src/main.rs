@@ -3,10 +3,14 @@ use std::path::PathBuf;
 use std::sync::OnceLock;
 
 use clap::{Parser, Subcommand};
+use log::error;
 
-use build::build;
+use build::{build, clean};
 use checkout::checkout;
+use hub::cancel;
+use hub::config::Backend;
 use hub::error::*;
+use hub::global::Inner;
 use hub::reporter::*;
 
 #[derive(Debug, Parser)]
@@ -31,6 +35,27 @@ pub enum BuildActions {
 
         #[arg(long = "build-in", required = false)]
         build_dir: Option<PathBuf>,
+
+        /// Expose the image through a specific backend. By default, the fastest available backend is used
+        #[arg(long, value_enum)]
+        backend: Option<Backend>,
+
+        /// Mount through FUSE even if superuser access is available
+        #[arg(long, action, default_value_t = false, conflicts_with = "backend")]
+        fuse: bool,
+    },
+
+    /// Removes the build directory, first cleaning up after any build which didn't shut down cleanly
+    Clean {
+        #[arg(index = 1)]
+        config: PathBuf,
+
+        #[arg(long = "build-in", required = false)]
+        build_dir: Option<PathBuf>,
+
+        /// Only unmount filesystems, stop the storage daemon and detach loop devices, keeping the build directory
+        #[arg(long, action, default_value_t = false)]
+        unmount: bool,
     },
 
     /// Extracts a particular recipe's source to a defined destination
@@ -45,6 +70,9 @@ pub enum BuildActions {
 
 pub static REPORTER: OnceLock<Reporter> = OnceLock::new();
 
+/// The exit status of an interrupted build, matching the shell's convention for SIGINT
+const INTERRUPTED: i32 = 130;
+
 pub fn main() -> Result<()> {
     env_logger::init();
 
@@ -53,11 +81,25 @@ pub fn main() -> Result<()> {
         .set(Reporter::new(args.report_mode))
         .expect("Unable to set reporter");
 
-    match args.action {
+    cancel::install()?;
+
+    match run(args.action) {
+        Err(err) if matches!(err.inner(), Inner::BuildError(BuildError::Interrupted)) => {
+            error!("Build interrupted");
+            std::process::exit(INTERRUPTED);
+        }
+        result => result,
+    }
+}
+
+fn run(action: BuildActions) -> Result<()> {
+    match action {
         BuildActions::Build {
             clean,
             config,
             build_dir,
+            backend,
+            fuse,
         } => {
             build(
                 match config.is_absolute() {
@@ -66,8 +108,22 @@ pub fn main() -> Result<()> {
                 },
                 clean,
                 build_dir,
+                backend,
+                fuse,
             )?
         }
+        BuildActions::Clean {
+            config,
+            build_dir,
+            unmount,
+        } => clean(
+            match config.is_absolute() {
+                true => config,
+                false => env::current_dir()?.join(config),
+            },
+            build_dir,
+            unmount,
+        )?,
         BuildActions::Checkout {
             destination,
             recipe,